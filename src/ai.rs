@@ -1,5 +1,14 @@
-use reqwest::Client;
+use crate::prompts::PromptTemplate;
+use crate::providers::{
+    AnthropicProvider, GeminiProvider, LlmProvider, OllamaProvider, OpenAiProvider,
+};
+use log::{info, warn};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, StatusCode};
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(Debug, Serialize)]
 pub struct ChatCompletionRequest {
@@ -7,125 +16,780 @@ pub struct ChatCompletionRequest {
     pub messages: Vec<Message>,
     pub temperature: Option<f32>,
     pub max_tokens: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     pub role: String,
     pub content: String,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct ChatCompletionResponse {
-    pub choices: Vec<Choice>,
-    pub usage: Option<Usage>,
+impl Message {
+    pub fn system(content: impl Into<String>) -> Self {
+        Self {
+            role: "system".to_string(),
+            content: content.into(),
+        }
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        Self {
+            role: "user".to_string(),
+            content: content.into(),
+        }
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self {
+            role: "assistant".to_string(),
+            content: content.into(),
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
-pub struct Choice {
-    pub message: ResponseMessage,
-    pub finish_reason: String,
+/// How a query is expanded before retrieval
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum QueryExpansion {
+    /// Several LLM-written reformulations of the query
+    Multi,
+    /// A hypothetical answer passage (HyDE)
+    Hyde,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct ResponseMessage {
-    pub role: String,
-    pub content: Option<String>,
+#[derive(Debug, Clone, Copy)]
+pub struct Expansion {
+    pub method: QueryExpansion,
+    /// Number of reformulations to generate (multi only)
+    pub count: usize,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct Usage {
-    pub prompt_tokens: i32,
-    pub completion_tokens: i32,
-    pub total_tokens: i32,
+const REWRITE_SYSTEM_MESSAGE: &str = "You rewrite follow-up questions into standalone search queries. Given a conversation and a follow-up question, reply with a single query that can be understood without the conversation. Reply with the query only, without quotes or explanation.";
+
+/// Delay before the first retry, doubled for every further attempt
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest wait between retries, also when the server's `Retry-After` asks for more
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Ways a request to the LLM can fail, each with advice on how to fix it
+#[derive(Debug)]
+pub enum LlmError {
+    /// The API key (or auth header) was rejected
+    Auth { status: StatusCode, message: String },
+    /// Still rate limited after every retry
+    RateLimited { message: String },
+    /// The prompt doesn't fit in the model's context window
+    ContextLength { message: String },
+    /// The model stopped before producing any content
+    Truncated { finish_reason: String },
+    /// No response within the configured timeout
+    Timeout(Duration),
+    /// Any other unsuccessful response
+    Api { status: StatusCode, message: String },
+    /// The request could not be sent or the response could not be read
+    Request(reqwest::Error),
+    /// A successful response with nothing in it
+    EmptyResponse,
+    /// A response that doesn't match the provider's API
+    InvalidResponse(serde_json::Error),
+    /// The provider reported an error partway through a streamed response
+    Stream(String),
 }
 
-pub struct OpenAiClient {
-    client: Client,
-    api_key: String,
-    url: String,
-    model: String,
+impl fmt::Display for LlmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LlmError::Auth { status, message } => write!(
+                f,
+                "Authentication failed ({}): {}. Check the API key, or the auth header in OPENAI_HEADERS.",
+                status, message
+            ),
+            LlmError::RateLimited { message } => write!(
+                f,
+                "Rate limited by the LLM provider: {}. Wait a moment and try again, or raise OPENAI_MAX_RETRIES.",
+                message
+            ),
+            LlmError::ContextLength { message } => write!(
+                f,
+                "The prompt is too long for the model: {}. Lower --top-k or use a model with a larger context window.",
+                message
+            ),
+            LlmError::Truncated { finish_reason } => write!(
+                f,
+                "Response was truncated (finish_reason: {}). Raise OPENAI_MAX_TOKENS or lower --top-k to shorten the prompt.",
+                finish_reason
+            ),
+            LlmError::Timeout(timeout) => write!(
+                f,
+                "No response from the LLM within {:?}. Raise OPENAI_TIMEOUT, or check that OPENAI_URL is reachable.",
+                timeout
+            ),
+            LlmError::Api { status, message } => {
+                write!(f, "API request failed ({}): {}", status, message)
+            }
+            LlmError::Request(e) => write!(f, "API request failed: {}", e),
+            LlmError::EmptyResponse => write!(f, "No choices in response"),
+            LlmError::InvalidResponse(e) => write!(
+                f,
+                "Unexpected response from the LLM: {}. Check that LLM_BACKEND matches the server at OPENAI_URL.",
+                e
+            ),
+            LlmError::Stream(message) => write!(f, "The LLM stopped with an error: {}", message),
+        }
+    }
 }
 
-impl OpenAiClient {
-    pub fn new(api_key: String, url: String, model: String) -> Self {
-        Self {
-            client: Client::new(),
-            api_key,
+impl std::error::Error for LlmError {}
+
+impl LlmError {
+    fn from_response(status: StatusCode, body: &str) -> Self {
+        let message = error_message(body);
+        let lowercase = body.to_lowercase();
+
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => LlmError::Auth { status, message },
+            StatusCode::TOO_MANY_REQUESTS => LlmError::RateLimited { message },
+            _ if lowercase.contains("context_length_exceeded")
+                || lowercase.contains("context length")
+                || lowercase.contains("context window")
+                || lowercase.contains("too many tokens")
+                || lowercase.contains("prompt is too long")
+                || lowercase.contains("maximum number of tokens") =>
+            {
+                LlmError::ContextLength { message }
+            }
+            _ => LlmError::Api { status, message },
+        }
+    }
+
+    fn is_retryable(&self) -> bool {
+        match self {
+            LlmError::RateLimited { .. } | LlmError::Timeout(_) => true,
+            LlmError::Api { status, .. } => status.is_server_error(),
+            LlmError::Request(e) => e.is_connect(),
+            _ => false,
+        }
+    }
+}
+
+impl From<reqwest::Error> for LlmError {
+    fn from(e: reqwest::Error) -> Self {
+        LlmError::Request(e)
+    }
+}
+
+impl From<serde_json::Error> for LlmError {
+    fn from(e: serde_json::Error) -> Self {
+        LlmError::InvalidResponse(e)
+    }
+}
+
+/// The `error.message` of an OpenAI-style error body, `error` if it is a string, or the raw
+/// body
+pub fn error_message(body: &str) -> String {
+    let json = serde_json::from_str::<serde_json::Value>(body).ok();
+    // Some providers wrap the error object in a list
+    let error = json.as_ref().map(|json| match json {
+        serde_json::Value::Array(items) => items.first().unwrap_or(json),
+        _ => json,
+    });
+
+    error
+        .and_then(|error| {
+            // Ollama and llama.cpp may send the message as a plain string
+            error["error"]["message"]
+                .as_str()
+                .or_else(|| error["error"].as_str())
+        })
+        .unwrap_or(body.trim())
+        .to_string()
+}
+
+/// `Retry-After` in seconds; the HTTP-date form is rare for APIs and falls back to backoff
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    parse_retry_after(
+        response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)?
+            .to_str()
+            .ok()?,
+    )
+}
+
+/// Negative, infinite or absurdly large values are ignored rather than trusted
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let seconds = value.trim().parse::<f64>().ok()?;
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+/// Send the request built by `request` until it succeeds, with exponential backoff (or the
+/// server's `Retry-After`, when given) between attempts. `failure` turns an error status and
+/// body, or a failure to send or read the response, into the caller's error and whether
+/// another attempt could succeed; at most `max_retries` are made.
+pub async fn send_with_retries<E: fmt::Display>(
+    max_retries: u32,
+    mut request: impl FnMut() -> Result<reqwest::RequestBuilder, E>,
+    mut failure: impl FnMut(Result<(StatusCode, String), reqwest::Error>) -> (E, bool),
+) -> Result<reqwest::Response, E> {
+    let mut attempt = 0;
+
+    loop {
+        let (error, retryable, delay) = match request()?.send().await {
+            Ok(response) if response.status().is_success() => return Ok(response),
+            Ok(response) => {
+                let status = response.status();
+                let delay = retry_after(&response);
+                let (error, retryable) = failure(response.text().await.map(|body| (status, body)));
+                (error, retryable, delay)
+            }
+            Err(e) => {
+                let (error, retryable) = failure(Err(e));
+                (error, retryable, None)
+            }
+        };
+
+        if !retryable || attempt >= max_retries {
+            return Err(error);
+        }
+
+        // Capped even when the server asks for longer, so it can't stall the CLI
+        let delay = delay
+            .unwrap_or_else(|| INITIAL_BACKOFF.saturating_mul(2u32.saturating_pow(attempt)))
+            .min(MAX_BACKOFF);
+        attempt += 1;
+        warn!(
+            "{} (retrying in {:?}, attempt {} of {})",
+            error, delay, attempt, max_retries
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// The API spoken by the LLM endpoint, chosen with `LLM_BACKEND`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LlmBackend {
+    /// OpenAI-compatible `/chat/completions`
+    OpenAi,
+    /// Ollama's native `/api/chat`
+    Ollama,
+    /// llama.cpp's server, through its OpenAI-compatible endpoint
+    LlamaCpp,
+    /// Anthropic's Messages API
+    Anthropic,
+    /// Google's Gemini `generateContent`
+    Gemini,
+}
+
+impl FromStr for LlmBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "openai" => Ok(LlmBackend::OpenAi),
+            "ollama" => Ok(LlmBackend::Ollama),
+            "llamacpp" | "llama.cpp" => Ok(LlmBackend::LlamaCpp),
+            "anthropic" => Ok(LlmBackend::Anthropic),
+            "gemini" => Ok(LlmBackend::Gemini),
+            _ => Err(format!(
+                "unknown LLM backend '{}', expected openai, ollama, llamacpp, anthropic or gemini",
+                s
+            )),
+        }
+    }
+}
+
+impl LlmBackend {
+    /// Where the API is served unless `OPENAI_URL` says otherwise. OpenAI-compatible
+    /// endpoints vary too much to have a default.
+    fn default_url(self) -> Option<&'static str> {
+        match self {
+            LlmBackend::OpenAi => None,
+            LlmBackend::Ollama => Some("http://localhost:11434/api/chat"),
+            LlmBackend::LlamaCpp => Some("http://localhost:8080/v1/chat/completions"),
+            LlmBackend::Anthropic => Some("https://api.anthropic.com/v1/messages"),
+            LlmBackend::Gemini => Some("https://generativelanguage.googleapis.com/v1beta"),
+        }
+    }
+
+    /// Provider-specific variable read when `OPENAI_API_KEY` is not set
+    fn api_key_var(self) -> Option<&'static str> {
+        match self {
+            LlmBackend::Anthropic => Some("ANTHROPIC_API_KEY"),
+            LlmBackend::Gemini => Some("GEMINI_API_KEY"),
+            _ => None,
+        }
+    }
+
+    fn provider(self) -> Box<dyn LlmProvider> {
+        match self {
+            LlmBackend::OpenAi | LlmBackend::LlamaCpp => Box::new(OpenAiProvider),
+            LlmBackend::Ollama => Box::new(OllamaProvider),
+            LlmBackend::Anthropic => Box::new(AnthropicProvider),
+            LlmBackend::Gemini => Box::new(GeminiProvider),
+        }
+    }
+}
+
+/// Endpoint, model and generation settings for the LLM, read from `LLM_BACKEND` and
+/// `OPENAI_*` variables
+#[derive(Debug, Clone)]
+pub struct LlmConfig {
+    pub backend: LlmBackend,
+    /// Sent the way the backend expects, e.g. as a Bearer token for OpenAI; leave unset for
+    /// endpoints authenticated by a custom header
+    pub api_key: Option<String>,
+    pub url: String,
+    pub model: String,
+    pub temperature: f32,
+    pub max_tokens: i32,
+    pub top_p: Option<f32>,
+    pub stop: Vec<String>,
+    /// Limit on a whole response, or on the gap between chunks when streaming
+    pub timeout: Option<Duration>,
+    /// Retries after a rate limit, server error or dropped connection
+    pub max_retries: u32,
+    /// Estimated tokens of retrieved sources to include in the prompt
+    pub context_budget: usize,
+    /// Sent as the `OpenAI-Organization` header
+    pub organization: Option<String>,
+    pub headers: Vec<(String, String)>,
+}
+
+fn parse_env<T: FromStr>(name: &str) -> Result<Option<T>, String> {
+    match std::env::var(name) {
+        Ok(value) if !value.trim().is_empty() => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("{} has an invalid value '{}'", name, value)),
+        _ => Ok(None),
+    }
+}
+
+impl LlmConfig {
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let var = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+        let required = |name: &str| {
+            var(name).ok_or_else(|| format!("{} must be set to generate answers", name))
+        };
+
+        // `Name: value` pairs separated by semicolons
+        let headers = var("OPENAI_HEADERS")
+            .unwrap_or_default()
+            .split(';')
+            .filter(|header| !header.trim().is_empty())
+            .map(|header| {
+                header
+                    .split_once(':')
+                    .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+                    .ok_or_else(|| {
+                        format!(
+                            "OPENAI_HEADERS entry '{}' must look like 'Name: value'",
+                            header
+                        )
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let backend = parse_env("LLM_BACKEND")?.unwrap_or(LlmBackend::OpenAi);
+        let url = match backend.default_url() {
+            Some(default_url) => var("OPENAI_URL").unwrap_or_else(|| default_url.to_string()),
+            None => required("OPENAI_URL")?,
+        };
+        // llama.cpp serves whichever model it was started with and ignores the name
+        let model = match backend {
+            LlmBackend::LlamaCpp => var("OPENAI_MODEL").unwrap_or_else(|| "default".to_string()),
+            _ => required("OPENAI_MODEL")?,
+        };
+
+        // 0 disables the timeout
+        let timeout_seconds = parse_env::<f64>("OPENAI_TIMEOUT")?.unwrap_or(120.0);
+        let timeout = if timeout_seconds == 0.0 {
+            None
+        } else {
+            Some(Duration::try_from_secs_f64(timeout_seconds).map_err(|_| {
+                format!(
+                    "OPENAI_TIMEOUT must be a non-negative number of seconds, got '{}'",
+                    timeout_seconds
+                )
+            })?)
+        };
+
+        Ok(Self {
+            backend,
+            api_key: var("OPENAI_API_KEY").or_else(|| backend.api_key_var().and_then(var)),
             url,
             model,
+            temperature: parse_env("OPENAI_TEMPERATURE")?.unwrap_or(0.7),
+            max_tokens: parse_env("OPENAI_MAX_TOKENS")?.unwrap_or(4096),
+            top_p: parse_env("OPENAI_TOP_P")?,
+            stop: var("OPENAI_STOP")
+                .map(|stop| stop.split(',').map(str::to_string).collect())
+                .unwrap_or_default(),
+            timeout,
+            max_retries: parse_env("OPENAI_MAX_RETRIES")?.unwrap_or(3),
+            context_budget: parse_env("OPENAI_CONTEXT_BUDGET")?.unwrap_or(8000),
+            organization: var("OPENAI_ORGANIZATION"),
+            headers,
+        })
+    }
+}
+
+pub struct LlmClient {
+    client: Client,
+    config: LlmConfig,
+    provider: Box<dyn LlmProvider>,
+}
+
+impl LlmClient {
+    pub fn new(config: LlmConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let provider = config.backend.provider();
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in provider.headers(&config) {
+            headers.insert(name, HeaderValue::from_str(&value)?);
+        }
+        for (name, value) in &config.headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
         }
+
+        let mut client = Client::builder().default_headers(headers);
+        if let Some(timeout) = config.timeout {
+            client = client.connect_timeout(timeout);
+        }
+
+        Ok(Self {
+            client: client.build()?,
+            config,
+            provider,
+        })
+    }
+
+    /// Build a client configured by the `LLM_BACKEND` and `OPENAI_*` environment variables
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        Self::new(LlmConfig::from_env()?)
+    }
+
+    pub fn config(&self) -> &LlmConfig {
+        &self.config
+    }
+
+    fn build_request(&self, messages: Vec<Message>) -> ChatCompletionRequest {
+        ChatCompletionRequest {
+            model: self.config.model.clone(),
+            messages,
+            temperature: Some(self.config.temperature),
+            max_tokens: Some(self.config.max_tokens),
+            top_p: self.config.top_p,
+            stop: self.config.stop.clone(),
+            stream: None,
+        }
+    }
+
+    /// Send a request, retrying rate limits, timeouts, server errors and dropped connections
+    async fn send(&self, request: &ChatCompletionRequest) -> Result<reqwest::Response, LlmError> {
+        send_with_retries(
+            self.config.max_retries,
+            || {
+                let mut builder = self
+                    .client
+                    .post(
+                        self.provider
+                            .url(&self.config, request.stream.unwrap_or(false)),
+                    )
+                    .header("Content-Type", "application/json")
+                    .json(&self.provider.request_body(request)?);
+                // A streamed response can legitimately take longer than the timeout in total, so
+                // `chat_stream` applies it between chunks instead
+                if let (Some(timeout), None) = (self.config.timeout, request.stream) {
+                    builder = builder.timeout(timeout);
+                }
+                Ok(builder)
+            },
+            |failure| {
+                let error = match failure {
+                    Ok((status, body)) => LlmError::from_response(status, &body),
+                    Err(e) if e.is_timeout() => {
+                        LlmError::Timeout(self.config.timeout.unwrap_or_default())
+                    }
+                    Err(e) => LlmError::from(e),
+                };
+                let retryable = error.is_retryable();
+                (error, retryable)
+            },
+        )
+        .await
     }
 
     pub async fn generate_response(
         &self,
+        prompt: &PromptTemplate,
+        query: &str,
+        search_results: &[crate::vector_store::SearchResult],
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        self.chat(prompt.messages(query, search_results)).await
+    }
+
+    /// Stream the response as it is generated, calling `on_token` as each piece of
+    /// content arrives. Returns the full response once the stream ends.
+    pub async fn generate_response_stream(
+        &self,
+        prompt: &PromptTemplate,
         query: &str,
-        search_results: &[crate::qdrant_client::SearchResult],
-        system_message: Option<&str>,
+        search_results: &[crate::vector_store::SearchResult],
+        on_token: impl FnMut(&str),
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        self.chat_stream(prompt.messages(query, search_results), on_token)
+            .await
+    }
+
+    /// Send a full conversation and return the assistant's reply
+    pub async fn chat(&self, messages: Vec<Message>) -> Result<String, Box<dyn std::error::Error>> {
+        self.complete(&self.build_request(messages)).await
+    }
+
+    async fn complete(
+        &self,
+        request: &ChatCompletionRequest,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let response = self.send(request).await?;
+
+        let completion = self.provider.parse_response(&response.text().await?)?;
+
+        if completion.finish_reason == "length" {
+            warn!("The response reached OPENAI_MAX_TOKENS and may be cut off");
+        }
+        completion.content.ok_or_else(|| {
+            LlmError::Truncated {
+                finish_reason: completion.finish_reason,
+            }
+            .into()
+        })
+    }
+
+    /// Like [`Self::chat`], but streamed, calling `on_token` as each piece of content arrives
+    pub async fn chat_stream(
+        &self,
+        messages: Vec<Message>,
+        mut on_token: impl FnMut(&str),
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let mut request = self.build_request(messages);
+        request.stream = Some(true);
+        let mut response = self.send(&request).await?;
+
+        // Raw bytes, since a network chunk can end partway through a line or UTF-8 character
+        let mut buffer = Vec::new();
+        let mut content = String::new();
+        let mut finish_reason = None;
+
+        'stream: loop {
+            let bytes = match self.config.timeout {
+                Some(timeout) => tokio::time::timeout(timeout, response.chunk())
+                    .await
+                    .map_err(|_| LlmError::Timeout(timeout))??,
+                None => response.chunk().await?,
+            };
+            let Some(bytes) = bytes else {
+                break;
+            };
+
+            buffer.extend_from_slice(&bytes);
+
+            while let Some(pos) = buffer.iter().position(|&b| b == b'\n') {
+                let line = buffer.drain(..=pos).collect::<Vec<u8>>();
+                let Some(event) = self
+                    .provider
+                    .parse_stream_line(&String::from_utf8_lossy(&line))?
+                else {
+                    continue;
+                };
+
+                if let Some(token) = event.token.filter(|token| !token.is_empty()) {
+                    on_token(&token);
+                    content.push_str(&token);
+                }
+                if event.finish_reason.is_some() {
+                    finish_reason = event.finish_reason;
+                }
+                if event.done {
+                    break 'stream;
+                }
+            }
+        }
+
+        if content.is_empty() {
+            return Err(LlmError::Truncated {
+                finish_reason: finish_reason.unwrap_or_else(|| "none".to_string()),
+            }
+            .into());
+        }
+        if finish_reason.as_deref() == Some("length") {
+            warn!("The response reached OPENAI_MAX_TOKENS and may be cut off");
+        }
+
+        Ok(content)
+    }
+
+    /// Rewrite a follow-up question into a standalone search query using the conversation
+    /// so far. Returns the question unchanged when there is no history.
+    pub async fn rewrite_query(
+        &self,
+        history: &[Message],
+        question: &str,
     ) -> Result<String, Box<dyn std::error::Error>> {
-        // Prepare context from search results
-        let context = search_results
+        if history.is_empty() {
+            return Ok(question.to_string());
+        }
+
+        let transcript = history
             .iter()
-            .enumerate()
-            .map(|(i, result)| {
-                format!(
-                    "[Source {}] File: {} (Score: {:.4})\nContent: {}\n",
-                    i + 1,
-                    result.file_name,
-                    result.similarity_score,
-                    result.chunk_content
-                )
-            })
+            .map(|message| format!("{}: {}", message.role, message.content))
             .collect::<Vec<_>>()
-            .join("\n");
+            .join("\n\n");
 
-        let system_message = system_message.unwrap_or("You are a helpful assistant that analyzes search results from a document database and provides comprehensive answers based on the information found.");
+        let mut request = self.build_request(vec![
+            Message::system(REWRITE_SYSTEM_MESSAGE),
+            Message::user(format!(
+                "Conversation:\n{}\n\nFollow-up question: {}",
+                transcript, question
+            )),
+        ]);
+        request.temperature = Some(0.0);
+        request.max_tokens = Some(256);
 
-        let user_content = format!(
-            "Based on the following search results from a document database, please provide a comprehensive answer to the user's query.\n\nUser Query: {}\n\nSearch Results:\n{}\n\nPlease provide a detailed answer based on the information found in the search results. If the search results don't contain enough information to fully answer the query, please indicate what additional information might be needed.",
-            query, context
-        );
+        let rewritten = self.complete(&request).await?;
+        let rewritten = rewritten.trim().trim_matches('"');
 
-        let request = ChatCompletionRequest {
-            model: self.model.clone(),
-            messages: vec![
-                Message {
-                    role: "system".to_string(),
-                    content: system_message.to_string(),
-                },
-                Message {
-                    role: "user".to_string(),
-                    content: user_content,
-                },
-            ],
-            temperature: Some(0.7),
-            max_tokens: Some(4096),
+        // Fall back to the raw question rather than searching for nothing
+        Ok(if rewritten.is_empty() {
+            question.to_string()
+        } else {
+            rewritten.to_string()
+        })
+    }
+
+    /// Generate extra texts to search for alongside `query`: reformulations of it, or a
+    /// hypothetical answer passage for HyDE. The original query is not included.
+    pub async fn expand_query(
+        &self,
+        query: &str,
+        expansion: &Expansion,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let system_message = match expansion.method {
+            QueryExpansion::Multi => format!("You generate alternative search queries for a document search engine. Write {} different reformulations of the user's query, using different wording, synonyms or more specific terms. Reply with one query per line, without numbering or explanation.", expansion.count),
+            QueryExpansion::Hyde => "Write a short passage, as it might appear in a document, that answers the user's question. Reply with the passage only and do not mention that it is hypothetical.".to_string(),
         };
 
-        let response = self
-            .client
-            .post(&self.url)
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .header("Content-Type", "application/json")
-            .json(&request)
-            .send()
-            .await?;
+        let mut request =
+            self.build_request(vec![Message::system(system_message), Message::user(query)]);
+        request.max_tokens = Some(512);
+        let response = self.complete(&request).await?;
 
-        if !response.status().is_success() {
-            let error_text = response.text().await?;
-            return Err(format!("API request failed: {}", error_text).into());
+        let queries = match expansion.method {
+            QueryExpansion::Multi => {
+                let mut queries: Vec<String> = Vec::new();
+                for line in response.lines() {
+                    // Models often number or bullet the list despite being asked not to
+                    let line = line
+                        .trim()
+                        .trim_start_matches(|c: char| c.is_ascii_digit() || "-*.)".contains(c))
+                        .trim()
+                        .trim_matches('"');
+                    if !line.is_empty()
+                        && !line.eq_ignore_ascii_case(query)
+                        && !queries.iter().any(|q| q.eq_ignore_ascii_case(line))
+                    {
+                        queries.push(line.to_string());
+                    }
+                }
+                queries.truncate(expansion.count);
+                queries
+            }
+            QueryExpansion::Hyde => vec![response.trim().to_string()],
+        };
+
+        for expanded in &queries {
+            info!("Expanded query: {}", expanded);
         }
+        Ok(queries)
+    }
+}
 
-        let chat_response: ChatCompletionResponse = response.json().await?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
 
-        if let Some(choice) = chat_response.choices.first() {
-            if let Some(content) = &choice.message.content {
-                Ok(content.clone())
-            } else {
-                Err(format!("Response was truncated (finish_reason: {}). Try increasing max_tokens or reducing the input size.", choice.finish_reason).into())
+    /// Answer one request per response, in order, on a free local port
+    async fn serve(responses: &'static [&'static str]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let read = socket.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                }
+                socket.write_all(response.as_bytes()).await.unwrap();
             }
-        } else {
-            Err("No choices in response".into())
+        });
+
+        url
+    }
+
+    const UNAVAILABLE: &str = "HTTP/1.1 503 Unavailable\r\nRetry-After: 0\r\nContent-Length: 4\r\nConnection: close\r\n\r\nbusy";
+    const OK: &str = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+
+    async fn send(url: &str, max_retries: u32) -> Result<reqwest::Response, String> {
+        let client = Client::new();
+        send_with_retries(
+            max_retries,
+            || Ok(client.get(url)),
+            |failure| match failure {
+                Ok((status, body)) => (format!("{}: {}", status.as_u16(), body), true),
+                Err(e) => (e.to_string(), false),
+            },
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn retries_until_success() {
+        let url = serve(&[UNAVAILABLE, UNAVAILABLE, OK]).await;
+        let response = send(&url, 2).await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_retries() {
+        let url = serve(&[UNAVAILABLE, UNAVAILABLE]).await;
+        assert_eq!(send(&url, 1).await.unwrap_err(), "503: busy");
+    }
+
+    #[test]
+    fn retry_after_values() {
+        assert_eq!(parse_retry_after("2"), Some(Duration::from_secs(2)));
+        assert_eq!(parse_retry_after(" 0.5 "), Some(Duration::from_millis(500)));
+        for invalid in ["-1", "1e30", "inf", "NaN", "Wed, 21 Oct 2015 07:28:00 GMT"] {
+            assert_eq!(parse_retry_after(invalid), None, "{}", invalid);
         }
     }
 }
@@ -0,0 +1,327 @@
+use crate::ai::{Expansion, LlmClient, Message};
+use crate::cli_ui::CliUI;
+use crate::context::{estimate_tokens, select_sources};
+use crate::grounding;
+use crate::markdown::{self, MarkdownStream};
+use crate::models::SearchModels;
+use crate::prompts::PromptTemplate;
+use crate::vector_store::{SearchOptions, SearchResult, VectorStore};
+use colored::Colorize;
+use log::info;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const HELP: &str = "Commands: /sources, /reset, /save [file], /help, /exit";
+
+/// One question and answer, with the query and sources used to produce it
+struct Turn {
+    question: String,
+    search_query: String,
+    answer: String,
+    sources: Vec<SearchResult>,
+}
+
+/// Everything that stays the same across turns of a chat session
+pub struct ChatConfig {
+    pub options: SearchOptions,
+    pub expansion: Option<Expansion>,
+    pub prompt: PromptTemplate,
+    pub stream: bool,
+    /// Check each answer against its sources (--strict)
+    pub strict: bool,
+    /// Skip the LLM unless a result's reranker score reaches this
+    pub min_relevance: Option<f32>,
+}
+
+/// The most recent turns that fit in `budget` estimated tokens, oldest first, and the tokens
+/// they use. Older turns are dropped so a long session doesn't outgrow the context window.
+fn history(turns: &[Turn], budget: usize) -> (Vec<Message>, usize) {
+    let mut tokens = 0;
+    let mut kept = 0;
+    for turn in turns.iter().rev() {
+        let cost = estimate_tokens(&turn.question) + estimate_tokens(&turn.answer);
+        if tokens + cost > budget {
+            break;
+        }
+        tokens += cost;
+        kept += 1;
+    }
+
+    let dropped = turns.len() - kept;
+    if dropped > 0 {
+        info!(
+            "Left {} earlier turns out of the history to fit the context budget",
+            dropped
+        );
+    }
+
+    let messages = turns[dropped..]
+        .iter()
+        .flat_map(|turn| {
+            [
+                Message::user(turn.question.as_str()),
+                Message::assistant(turn.answer.as_str()),
+            ]
+        })
+        .collect();
+    (messages, tokens)
+}
+
+fn print_sources(sources: &[SearchResult], ui: &CliUI) {
+    if sources.is_empty() {
+        ui.print_warning("No sources yet, ask a question first.");
+        return;
+    }
+
+    ui.print_section("Sources");
+    for (i, source) in sources.iter().enumerate() {
+        println!(
+            "  [{}] {} (chunk {}, score {:.4})",
+            i + 1,
+            source.file_path.bright_blue(),
+            source.chunk_index,
+            source.similarity_score
+        );
+    }
+}
+
+/// Write the conversation as Markdown, returning the path it was saved to
+fn save_transcript(
+    turns: &[Turn],
+    path: Option<&str>,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let path = match path {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            PathBuf::from(format!("chat-{}.md", now))
+        }
+    };
+
+    let mut transcript = String::from("# Chat transcript\n");
+    for turn in turns {
+        transcript.push_str(&format!("\n## You\n\n{}\n", turn.question));
+        if turn.search_query != turn.question {
+            transcript.push_str(&format!("\n_Searched for: {}_\n", turn.search_query));
+        }
+        transcript.push_str(&format!("\n## Assistant\n\n{}\n", turn.answer));
+        if !turn.sources.is_empty() {
+            transcript.push_str("\nSources:\n\n");
+            for source in &turn.sources {
+                transcript.push_str(&format!(
+                    "- {} (chunk {})\n",
+                    source.file_path, source.chunk_index
+                ));
+            }
+        }
+    }
+
+    fs::write(&path, transcript)?;
+    Ok(path)
+}
+
+async fn ask(
+    question: &str,
+    turns: &[Turn],
+    client: &LlmClient,
+    vector_store: &impl VectorStore,
+    models: &mut SearchModels,
+    config: &ChatConfig,
+    ui: &CliUI,
+) -> Result<Turn, Box<dyn std::error::Error>> {
+    // The history gets at most half the context budget, the sources get what it leaves
+    let budget = client.config().context_budget;
+    let (mut messages, history_tokens) = history(turns, budget / 2);
+
+    let rewrite_spinner = ui.show_loading("Understanding question...");
+    let search_query = client.rewrite_query(&messages, question).await;
+    rewrite_spinner.finish_and_clear();
+    let search_query = search_query?;
+    if search_query != question {
+        info!("Rewrote follow-up as: {}", search_query);
+    }
+
+    let extra_queries = match &config.expansion {
+        Some(expansion) => {
+            let expand_spinner = ui.show_loading("Expanding query...");
+            let queries = client.expand_query(&search_query, expansion).await;
+            expand_spinner.finish_and_clear();
+            queries?
+        }
+        None => Vec::new(),
+    };
+
+    let search_spinner = ui.show_loading("Searching vector database...");
+    let sources = vector_store
+        .hybrid_search(&search_query, &extra_queries, models, &config.options)
+        .await;
+    search_spinner.finish_and_clear();
+    let sources = sources?;
+    if let Some(threshold) = config.min_relevance {
+        if !grounding::is_relevant(&sources, threshold) {
+            let answer = "Nothing relevant was found in the documents.".to_string();
+            ui.print_warning(&answer);
+            return Ok(Turn {
+                question: question.to_string(),
+                search_query,
+                answer,
+                sources: Vec::new(),
+            });
+        }
+    }
+    if sources.is_empty() {
+        ui.print_warning("No matching documents, answering from the conversation only.");
+    }
+
+    let context = select_sources(&sources, budget - history_tokens);
+    crate::report_context(&context, sources.len(), ui);
+    // Keep what the model saw, so /sources matches the [Source N] numbers in the answer
+    let sources = context.sources;
+
+    // The history goes between the template's system message and the new question
+    let mut prompt = config.prompt.messages(&search_query, &sources);
+    let user_prompt = prompt.pop();
+    messages.splice(0..0, prompt);
+    messages.extend(user_prompt);
+
+    let llm_spinner = ui.show_loading("Generating response from LLM...");
+    let answer = if config.stream {
+        let mut markdown_stream = MarkdownStream::new();
+        let mut started = false;
+
+        let answer = client
+            .chat_stream(messages, |token| {
+                if !started {
+                    llm_spinner.finish_and_clear();
+                    println!();
+                    started = true;
+                }
+                print!("{}", markdown_stream.push(token).bright_white());
+                io::stdout().flush().ok();
+            })
+            .await;
+
+        llm_spinner.finish_and_clear();
+        println!("{}", markdown_stream.finish().bright_white());
+        answer?
+    } else {
+        let answer = client.chat(messages).await;
+        llm_spinner.finish_and_clear();
+        let answer = answer?;
+        println!("\n{}", markdown::format_markdown(&answer).bright_white());
+        answer
+    };
+
+    if config.strict {
+        crate::report_unsupported(&grounding::unsupported_sentences(&answer, &sources), ui);
+    }
+
+    Ok(Turn {
+        question: question.to_string(),
+        search_query,
+        answer,
+        sources,
+    })
+}
+
+/// Run the interactive chat loop until the user exits
+pub async fn run(
+    vector_store: &impl VectorStore,
+    models: &mut SearchModels,
+    config: &ChatConfig,
+    ui: &CliUI,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let client = LlmClient::from_env()?;
+    let mut turns: Vec<Turn> = Vec::new();
+
+    ui.print_section("Chat");
+    ui.print_info("Ask questions about your documents. Follow-ups keep the conversation context.");
+    ui.print_info(HELP);
+
+    loop {
+        // ask_input returns an empty string on Ctrl-C or Ctrl-D
+        let input = ui.ask_input("You");
+        let input = input.trim();
+        if input.is_empty() {
+            break;
+        }
+
+        if let Some(command) = input.strip_prefix('/') {
+            let (command, argument) = match command.split_once(char::is_whitespace) {
+                Some((command, argument)) => (command, Some(argument.trim())),
+                None => (command, None),
+            };
+
+            match command {
+                "exit" | "quit" => break,
+                "help" => ui.print_info(HELP),
+                "sources" => print_sources(
+                    turns.last().map_or(&[][..], |turn| turn.sources.as_slice()),
+                    ui,
+                ),
+                "reset" => {
+                    turns.clear();
+                    ui.print_success("Conversation cleared");
+                }
+                "save" => match save_transcript(&turns, argument) {
+                    Ok(path) => {
+                        ui.print_success(&format!("Saved conversation to {}", path.display()))
+                    }
+                    Err(e) => ui.print_error(&format!("Failed to save conversation: {}", e)),
+                },
+                _ => ui.print_warning(&format!("Unknown command '/{}'. {}", command, HELP)),
+            }
+            continue;
+        }
+
+        // A failed turn is reported but doesn't end the session or enter the history
+        match ask(input, &turns, &client, vector_store, models, config, ui).await {
+            Ok(turn) => turns.push(turn),
+            Err(e) => ui.print_error(&format!("Failed to answer: {}", e)),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn turn(question: &str, answer: &str) -> Turn {
+        Turn {
+            question: question.to_string(),
+            search_query: question.to_string(),
+            answer: answer.to_string(),
+            sources: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn history_drops_oldest_turns_over_budget() {
+        // 2 + 3 estimated tokens per turn
+        let turns = [
+            turn("first q.", "first answer"),
+            turn("second q", "second answ."),
+            turn("third q.", "third answer"),
+        ];
+
+        let (messages, tokens) = history(&turns, 100);
+        assert_eq!(messages.len(), 6);
+        assert_eq!(tokens, 15);
+
+        let (messages, tokens) = history(&turns, 12);
+        let contents: Vec<&str> = messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(
+            contents,
+            ["second q", "second answ.", "third q.", "third answer"]
+        );
+        assert_eq!(tokens, 10);
+
+        let (messages, tokens) = history(&turns, 4);
+        assert!(messages.is_empty());
+        assert_eq!(tokens, 0);
+    }
+}
@@ -1,64 +1,139 @@
 use colored::*;
 use console::Term;
 use dialoguer::{Confirm, Input, Select};
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use serde::Serialize;
+use std::fmt::Display;
 use std::time::Duration;
 use tabled::{
     settings::{Alignment, Style},
     Table, Tabled,
 };
 
+/// Format of the results written to stdout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Decorated, human-readable output
+    Text,
+    /// A single JSON document per command
+    Json,
+    /// One JSON object per line, written as results become available
+    Ndjson,
+}
+
 /// Modern CLI UI utilities for enhanced user experience
 pub struct CliUI {
     multi_progress: MultiProgress,
     term: Term,
+    output: OutputFormat,
+    quiet: bool,
 }
 
 impl CliUI {
     pub fn new() -> Self {
+        Self::with_options(OutputFormat::Text, false)
+    }
+
+    /// `quiet` hides progress bars and status messages, keeping warnings, errors and results
+    pub fn with_options(output: OutputFormat, quiet: bool) -> Self {
+        let multi_progress = if quiet {
+            MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+        } else {
+            MultiProgress::new()
+        };
+
         Self {
-            multi_progress: MultiProgress::new(),
+            multi_progress,
             term: Term::stdout(),
+            output,
+            quiet,
+        }
+    }
+
+    /// Whether results are printed for people rather than scripts
+    pub fn is_text(&self) -> bool {
+        self.output == OutputFormat::Text
+    }
+
+    /// Decoration goes to stdout for text output, and to stderr when stdout carries JSON
+    fn print_line(&self, line: impl Display) {
+        if self.is_text() {
+            println!("{}", line);
+        } else {
+            eprintln!("{}", line);
+        }
+    }
+
+    /// Print a value as a JSON document (`--output json` only)
+    pub fn emit_json<T: Serialize>(&self, value: &T) {
+        if self.output == OutputFormat::Json {
+            match serde_json::to_string_pretty(value) {
+                Ok(json) => println!("{}", json),
+                Err(e) => self.print_error(&format!("Failed to serialize output: {}", e)),
+            }
+        }
+    }
+
+    /// Print a value as a single JSON line (`--output ndjson` only)
+    pub fn emit_ndjson<T: Serialize>(&self, value: &T) {
+        if self.output == OutputFormat::Ndjson {
+            match serde_json::to_string(value) {
+                Ok(json) => println!("{}", json),
+                Err(e) => self.print_error(&format!("Failed to serialize output: {}", e)),
+            }
         }
     }
 
     /// Print a beautiful header with the app name
     pub fn print_header(&self) {
-        println!();
-        println!("{}", "=".repeat(80).bright_blue());
-        println!(
-            "{}",
-            "🚀 FILE CRAWLER & SEMANTIC SEARCH".bright_cyan().bold()
-        );
-        println!("{}", "=".repeat(80).bright_blue());
-        println!();
+        if self.quiet {
+            return;
+        }
+
+        self.print_line("");
+        self.print_line("=".repeat(80).bright_blue());
+        self.print_line("🚀 FILE CRAWLER & SEMANTIC SEARCH".bright_cyan().bold());
+        self.print_line("=".repeat(80).bright_blue());
+        self.print_line("");
     }
 
     /// Print a section header with styling
     pub fn print_section(&self, title: &str) {
-        println!();
-        println!("{}", format!("📁 {}", title).bright_yellow().bold());
-        println!("{}", "─".repeat(title.len() + 4).bright_yellow());
+        if self.quiet {
+            return;
+        }
+
+        self.print_line("");
+        self.print_line(format!("📁 {}", title).bright_yellow().bold());
+        self.print_line("─".repeat(title.len() + 4).bright_yellow());
     }
 
     /// Print a success message with checkmark
     pub fn print_success(&self, message: &str) {
-        println!("{} {}", "✅".green(), message.green());
+        if self.quiet {
+            return;
+        }
+
+        self.print_line(format!("{} {}", "✅".green(), message.green()));
     }
 
     /// Print an error message with X mark
     pub fn print_error(&self, message: &str) {
-        println!("{} {}", "❌".red(), message.red());
+        self.print_line(format!("{} {}", "❌".red(), message.red()));
     }
 
     /// Print a warning message with warning sign
     pub fn print_warning(&self, message: &str) {
-        println!("{} {}", "⚠️".yellow(), message.yellow());
+        self.print_line(format!("{} {}", "⚠️".yellow(), message.yellow()));
     }
 
     /// Print an info message with info icon
     pub fn print_info(&self, message: &str) {
-        println!("{} {}", "ℹ️".blue(), message.blue());
+        if self.quiet {
+            return;
+        }
+
+        self.print_line(format!("{} {}", "ℹ️".blue(), message.blue()));
     }
 
     /// Create a progress bar for file processing
@@ -116,19 +191,46 @@ impl CliUI {
             .with(Alignment::left())
             .to_string();
 
-        println!("{}", table_str);
+        self.print_line(table_str);
+    }
+
+    /// Print a table of documents stored in the index
+    pub fn print_documents_table(&self, documents: &[DocumentInfo]) {
+        if documents.is_empty() {
+            self.print_warning("No indexed documents found");
+            return;
+        }
+
+        let table_str = Table::new(documents)
+            .with(Style::modern())
+            .with(Alignment::left())
+            .to_string();
+
+        self.print_line(table_str);
+    }
+
+    /// Print retrieval metrics for each evaluated search mode
+    pub fn print_eval_table(&self, k: usize, rows: &[EvalInfo]) {
+        self.print_line("");
+        self.print_line(format!("📊 Retrieval metrics @ {}", k).bright_cyan().bold());
+
+        let table_str = Table::new(rows)
+            .with(Style::modern())
+            .with(Alignment::left())
+            .to_string();
+
+        self.print_line(table_str);
     }
 
     /// Print search results in a beautiful format
     pub fn print_search_results(&self, query: &str, results: &[SearchResult]) {
-        println!();
-        println!(
-            "{}",
+        self.print_line("");
+        self.print_line(
             format!("🔍 Search Results for: '{}'", query)
                 .bright_cyan()
-                .bold()
+                .bold(),
         );
-        println!("{}", "=".repeat(60).bright_cyan());
+        self.print_line("=".repeat(60).bright_cyan());
 
         if results.is_empty() {
             self.print_warning("No results found");
@@ -136,21 +238,21 @@ impl CliUI {
         }
 
         for (i, result) in results.iter().enumerate() {
-            println!();
-            println!(
+            self.print_line("");
+            self.print_line(format!(
                 "{} {}",
                 format!("{}.", i + 1).bright_yellow().bold(),
                 result.file_name.bright_white().bold()
-            );
-            println!("{} {}", "📄 File:".blue(), result.file_path.blue());
-            println!(
+            ));
+            self.print_line(format!("{} {}", "📄 File:".blue(), result.file_path.blue()));
+            self.print_line(format!(
                 "{} {}",
                 "📊 Score:".green(),
                 format!("{:.3}", result.score).green()
-            );
-            println!("{}", "─".repeat(50).dimmed());
-            println!("{}", result.content.trim().white());
-            println!();
+            ));
+            self.print_line("─".repeat(50).dimmed());
+            self.print_line(result.content.trim().white());
+            self.print_line("");
         }
     }
 
@@ -189,9 +291,13 @@ impl CliUI {
 
     /// Print a completion message with celebration
     pub fn print_completion(&self, message: &str) {
-        println!();
-        println!("🎉 {}", message.bright_green().bold());
-        println!();
+        if self.quiet {
+            return;
+        }
+
+        self.print_line("");
+        self.print_line(format!("🎉 {}", message.bright_green().bold()));
+        self.print_line("");
     }
 
     /// Clear the screen
@@ -201,7 +307,7 @@ impl CliUI {
 
     /// Print a separator line
     pub fn print_separator(&self) {
-        println!("{}", "─".repeat(80).dimmed());
+        self.print_line("─".repeat(80).dimmed());
     }
 }
 
@@ -218,6 +324,60 @@ pub struct FileInfo {
     pub status: String,
 }
 
+/// Indexed document information for table display
+#[derive(Tabled)]
+pub struct DocumentInfo {
+    #[tabled(rename = "Path")]
+    pub path: String,
+    #[tabled(rename = "Size")]
+    pub size: String,
+    #[tabled(rename = "Modified")]
+    pub modified: String,
+    #[tabled(rename = "Chunks")]
+    pub chunks: u64,
+    #[tabled(rename = "Hash")]
+    pub hash: String,
+}
+
+/// Retrieval metrics for one search mode, for table display
+#[derive(Tabled)]
+pub struct EvalInfo {
+    #[tabled(rename = "Mode")]
+    pub mode: String,
+    #[tabled(rename = "Recall")]
+    pub recall: String,
+    #[tabled(rename = "MRR")]
+    pub mrr: String,
+    #[tabled(rename = "nDCG")]
+    pub ndcg: String,
+}
+
+/// Format a Unix timestamp as a UTC `YYYY-MM-DD HH:MM` string
+pub fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86_400) as i64;
+    let seconds_of_day = timestamp % 86_400;
+
+    // Civil-from-days conversion (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds_of_day / 3_600,
+        (seconds_of_day % 3_600) / 60
+    )
+}
+
 /// Search result information
 pub struct SearchResult {
     pub file_name: String,
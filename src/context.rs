@@ -0,0 +1,112 @@
+use crate::vector_store::SearchResult;
+
+/// The sources that fit in the LLM's context budget
+pub struct Context {
+    /// Sources in score order, with adjacent chunks of a file merged into one
+    pub sources: Vec<SearchResult>,
+    /// Retrieved chunks included in `sources`
+    pub chunks_used: usize,
+    /// Retrieved chunks dropped as duplicates of a higher-scoring one
+    pub duplicates: usize,
+    /// Retrieved chunks left out because the budget was used up
+    pub over_budget: usize,
+    /// Estimated tokens used by the sources
+    pub tokens: usize,
+}
+
+/// Rough token count, about four characters per token for English text. Good enough for
+/// budgeting without loading the LLM's tokenizer.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+fn normalize(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// Pick the sources to send to the LLM: highest score first, skipping passages already
+/// covered by a better source, until `budget` tokens are used. Adjacent chunks from the same
+/// file are then merged so the model sees them as one passage.
+pub fn select_sources(results: &[SearchResult], budget: usize) -> Context {
+    let mut ranked = results.iter().collect::<Vec<_>>();
+    ranked.sort_by(|a, b| b.similarity_score.total_cmp(&a.similarity_score));
+
+    let mut selected: Vec<SearchResult> = Vec::new();
+    let mut seen: Vec<String> = Vec::new();
+    let mut duplicates = 0;
+    let mut tokens = 0;
+
+    for result in ranked {
+        // The same passage can appear in several files, or inside a longer chunk
+        let normalized = normalize(&result.chunk_content);
+        if seen.iter().any(|s| s.contains(&normalized)) {
+            duplicates += 1;
+            continue;
+        }
+
+        let cost = estimate_tokens(&result.chunk_content);
+        if tokens + cost > budget {
+            if selected.is_empty() {
+                // Better a truncated top source than no context at all
+                let mut truncated = result.clone();
+                truncated.chunk_content = result.chunk_content.chars().take(budget * 4).collect();
+                tokens = estimate_tokens(&truncated.chunk_content);
+                selected.push(truncated);
+            }
+            break;
+        }
+
+        tokens += cost;
+        seen.push(normalized);
+        selected.push(result.clone());
+    }
+
+    let chunks_used = selected.len();
+    Context {
+        sources: merge_adjacent(selected),
+        chunks_used,
+        duplicates,
+        // Anything after the budget ran out, duplicate or not, counts as over budget
+        over_budget: results.len() - chunks_used - duplicates,
+        tokens,
+    }
+}
+
+/// Merge runs of consecutive chunks from the same file, keeping the best score of the run
+fn merge_adjacent(mut selected: Vec<SearchResult>) -> Vec<SearchResult> {
+    selected.sort_by(|a, b| {
+        a.file_path
+            .cmp(&b.file_path)
+            .then(a.chunk_index.cmp(&b.chunk_index))
+    });
+
+    // Each merged source with the index of the last chunk in it
+    let mut merged: Vec<(SearchResult, i32)> = Vec::new();
+    for result in selected {
+        match merged.last_mut() {
+            Some((source, last_index))
+                if source.file_path == result.file_path
+                    && *last_index + 1 == result.chunk_index =>
+            {
+                source.chunk_content.push('\n');
+                source.chunk_content.push_str(&result.chunk_content);
+                source.similarity_score = source.similarity_score.max(result.similarity_score);
+                *last_index = result.chunk_index;
+            }
+            _ => {
+                let last_index = result.chunk_index;
+                merged.push((result, last_index));
+            }
+        }
+    }
+
+    let mut sources = merged
+        .into_iter()
+        .map(|(source, _)| source)
+        .collect::<Vec<_>>();
+    sources.sort_by(|a, b| b.similarity_score.total_cmp(&a.similarity_score));
+    sources
+}
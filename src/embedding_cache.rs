@@ -0,0 +1,211 @@
+use crate::embeddings::Embedder;
+use crate::models::{self, ModelCache};
+use fastembed::{SparseEmbedding, SparseTextEmbedding};
+use log::{debug, warn};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// An embedding as stored on disk: little-endian numbers, no header
+trait Entry: Sized {
+    fn encode(&self) -> Vec<u8>;
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+fn decode_f32s(bytes: &[u8]) -> Option<Vec<f32>> {
+    bytes.len().is_multiple_of(4).then(|| {
+        bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect()
+    })
+}
+
+impl Entry for Vec<f32> {
+    fn encode(&self) -> Vec<u8> {
+        self.iter().flat_map(|value| value.to_le_bytes()).collect()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        decode_f32s(bytes).filter(|values| !values.is_empty())
+    }
+}
+
+/// The number of terms, then their indices, then their weights
+impl Entry for SparseEmbedding {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = (self.indices.len() as u32).to_le_bytes().to_vec();
+        bytes.extend(self.indices.iter().flat_map(|i| (*i as u32).to_le_bytes()));
+        bytes.extend(self.values.iter().flat_map(|value| value.to_le_bytes()));
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let count = u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?) as usize;
+        let (indices, values) = bytes[4..].split_at_checked(count * 4)?;
+        let values = decode_f32s(values).filter(|values| values.len() == count)?;
+        let indices = indices
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+            .collect();
+
+        Some(SparseEmbedding { indices, values })
+    }
+}
+
+/// Embeddings of indexed chunks on disk, keyed by model name and the hash of the exact text
+/// the model saw, so re-indexing an edited document only embeds the chunks that changed
+pub struct EmbeddingCache {
+    dir: Option<PathBuf>,
+}
+
+impl EmbeddingCache {
+    /// `EMBEDDING_CACHE_DIR`, by default `embeddings` in the model cache; `EMBEDDING_CACHE=off`
+    /// disables it
+    pub fn from_env(models: &ModelCache) -> Self {
+        let disabled = env::var("EMBEDDING_CACHE")
+            .is_ok_and(|value| matches!(value.to_lowercase().as_str(), "0" | "false" | "off"));
+        let dir = env::var_os("EMBEDDING_CACHE_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| models.dir().join("embeddings"));
+
+        Self {
+            dir: (!disabled).then_some(dir),
+        }
+    }
+
+    /// `<dir>/<org>--<model>/<first 2 hash digits>/<rest of the hash>`, like git objects,
+    /// so no directory holds more than a few thousand files
+    fn path(&self, model: &str, input: &str) -> Option<PathBuf> {
+        let hash = format!("{:x}", md5::compute(input));
+        let (shard, name) = hash.split_at(2);
+        Some(
+            self.dir
+                .as_ref()?
+                .join(model.replace('/', "--"))
+                .join(shard)
+                .join(name),
+        )
+    }
+
+    fn get<T: Entry>(&self, model: &str, input: &str) -> Option<T> {
+        let bytes = fs::read(self.path(model, input)?).ok()?;
+        T::decode(&bytes)
+    }
+
+    /// Failing to write only costs a recomputation next time, so errors are logged, not returned
+    fn put<T: Entry>(&self, model: &str, input: &str, embedding: &T) {
+        let Some(path) = self.path(model, input) else {
+            return;
+        };
+        // Write to a temporary file and rename it, so an interrupted run never leaves a
+        // truncated entry behind
+        let temp = path.with_extension(format!("tmp{}", std::process::id()));
+        let written = fs::create_dir_all(path.parent().unwrap())
+            .and_then(|_| fs::write(&temp, embedding.encode()))
+            .and_then(|_| fs::rename(&temp, &path));
+        if let Err(e) = written {
+            warn!("Failed to cache an embedding in {}: {}", path.display(), e);
+            let _ = fs::remove_file(&temp);
+        }
+    }
+
+    /// Cached embeddings of `inputs`, and the positions of those that still need embedding
+    fn lookup<T: Entry>(&self, model: &str, inputs: &[String]) -> (Vec<Option<T>>, Vec<usize>) {
+        let found = inputs
+            .iter()
+            .map(|input| self.get(model, input))
+            .collect::<Vec<Option<T>>>();
+        let missing = (0..inputs.len()).filter(|&i| found[i].is_none()).collect();
+        (found, missing)
+    }
+
+    /// Fill the gaps left by `lookup` with freshly computed embeddings and cache them
+    fn complete<T: Entry>(
+        &self,
+        model: &str,
+        inputs: &[String],
+        mut found: Vec<Option<T>>,
+        missing: Vec<usize>,
+        computed: Vec<T>,
+    ) -> Result<Vec<T>, Box<dyn std::error::Error>> {
+        if computed.len() != missing.len() {
+            return Err(format!(
+                "{} returned {} embeddings for {} chunks",
+                model,
+                computed.len(),
+                missing.len()
+            )
+            .into());
+        }
+
+        debug!(
+            "Reused {} of {} cached {} embeddings",
+            inputs.len() - missing.len(),
+            inputs.len(),
+            model
+        );
+        for (i, embedding) in missing.into_iter().zip(computed) {
+            self.put(model, &inputs[i], &embedding);
+            found[i] = Some(embedding);
+        }
+
+        Ok(found.into_iter().flatten().collect())
+    }
+
+    /// Dense embeddings of the chunks, only running the model on those not cached yet.
+    /// Returns the embeddings and how many came from the cache.
+    pub async fn embed_dense(
+        &self,
+        embedder: &mut Embedder,
+        chunks: &[String],
+    ) -> Result<(Vec<Vec<f32>>, usize), Box<dyn std::error::Error>> {
+        let model = embedder.model_name()?;
+        let inputs = chunks
+            .iter()
+            .map(|chunk| embedder.passage_input(chunk))
+            .collect::<Vec<_>>();
+
+        let (found, missing) = self.lookup(&model, &inputs);
+        let reused = inputs.len() - missing.len();
+        let computed = if missing.is_empty() {
+            Vec::new()
+        } else {
+            let texts = missing.iter().map(|&i| inputs[i].clone()).collect();
+            embedder.embed_inputs(texts).await?
+        };
+
+        Ok((
+            self.complete(&model, &inputs, found, missing, computed)?,
+            reused,
+        ))
+    }
+
+    /// Sparse embeddings of the chunks, only running the model on those not cached yet.
+    /// Returns the embeddings and how many came from the cache.
+    pub fn embed_sparse(
+        &self,
+        sparse_model: &mut SparseTextEmbedding,
+        chunks: &[String],
+    ) -> Result<(Vec<SparseEmbedding>, usize), Box<dyn std::error::Error>> {
+        let model = models::sparse_model_name();
+        let inputs = chunks
+            .iter()
+            .map(|chunk| models::SPARSE_PREFIXES.passage(chunk))
+            .collect::<Vec<_>>();
+
+        let (found, missing) = self.lookup(&model, &inputs);
+        let reused = inputs.len() - missing.len();
+        let computed = if missing.is_empty() {
+            Vec::new()
+        } else {
+            let texts = missing.iter().map(|&i| inputs[i].clone()).collect();
+            sparse_model.embed(texts, None)?
+        };
+
+        Ok((
+            self.complete(&model, &inputs, found, missing, computed)?,
+            reused,
+        ))
+    }
+}
@@ -0,0 +1,255 @@
+use crate::ai::{error_message, send_with_retries};
+use crate::models::{self, ModelCache, Prefixes};
+use crate::vector_store::DENSE_DIMENSIONS;
+use fastembed::TextEmbedding;
+use log::debug;
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Where dense embeddings are computed, chosen with `EMBEDDING_BACKEND`
+pub enum Embedder {
+    /// The fastembed model, run in-process
+    Local(Box<TextEmbedding>),
+    /// An OpenAI-compatible `/v1/embeddings` endpoint
+    Remote(RemoteEmbedder),
+}
+
+impl Embedder {
+    /// `EMBEDDING_BACKEND=openai` sends text to `EMBEDDING_URL`; the default loads the local model
+    pub fn from_env(cache: &ModelCache) -> Result<Self, Box<dyn std::error::Error>> {
+        let backend = env::var("EMBEDDING_BACKEND").unwrap_or_default();
+        match backend.to_lowercase().as_str() {
+            "" | "local" => Ok(Self::Local(Box::new(cache.load_dense()?))),
+            "openai" => Ok(Self::Remote(RemoteEmbedder::from_env()?)),
+            _ => Err(format!(
+                "Unknown EMBEDDING_BACKEND '{}', expected local or openai",
+                backend
+            )
+            .into()),
+        }
+    }
+
+    /// Name of the model producing the vectors, e.g. to key cached embeddings
+    pub fn model_name(&self) -> Result<String, Box<dyn std::error::Error>> {
+        match self {
+            Self::Local(_) => models::dense_model_name(),
+            Self::Remote(remote) => Ok(remote.model.clone()),
+        }
+    }
+
+    /// The text the model embeds for a passage, including its prefix
+    pub fn passage_input(&self, passage: &str) -> String {
+        match self {
+            Self::Local(_) => models::dense_prefixes().passage(passage),
+            Self::Remote(remote) => remote.prefixes.passage(passage),
+        }
+    }
+
+    /// Embed texts exactly as given, without adding prefixes
+    pub async fn embed_inputs(
+        &mut self,
+        inputs: Vec<String>,
+    ) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error>> {
+        match self {
+            Self::Local(model) => Ok(model.embed(inputs, None)?),
+            Self::Remote(remote) => remote.embed(inputs).await,
+        }
+    }
+
+    /// Embed a search query, with the model's query prefix
+    pub async fn embed_query(
+        &mut self,
+        query: &str,
+    ) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+        let embeddings = match self {
+            Self::Local(model) => model.embed(vec![models::dense_prefixes().query(query)], None)?,
+            Self::Remote(remote) => remote.embed(vec![remote.prefixes.query(query)]).await?,
+        };
+
+        embeddings
+            .into_iter()
+            .next()
+            .ok_or_else(|| "The embedding model returned no vector for the query".into())
+    }
+}
+
+/// Vectors of a different size than the index stores. Unlike a failed request this can't
+/// succeed for any other document, so indexing stops.
+#[derive(Debug)]
+pub struct DimensionMismatch {
+    model: String,
+    dimensions: usize,
+}
+
+impl fmt::Display for DimensionMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} returned {}-dimensional embeddings but the collection stores {}; \
+             EMBEDDING_MODEL must produce the same vectors as the local model",
+            self.model, self.dimensions, DENSE_DIMENSIONS
+        )
+    }
+}
+
+impl std::error::Error for DimensionMismatch {}
+
+#[derive(Serialize)]
+struct EmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingData {
+    embedding: Vec<f32>,
+    index: usize,
+}
+
+/// Client for an OpenAI-compatible embeddings endpoint, such as text-embeddings-inference,
+/// vLLM or Ollama serving the same model as the local backend
+pub struct RemoteEmbedder {
+    client: Client,
+    url: String,
+    model: String,
+    api_key: Option<String>,
+    batch_size: usize,
+    max_retries: u32,
+    prefixes: Prefixes,
+}
+
+/// A numeric setting, or `default` when unset or empty
+pub fn env_number<T: std::str::FromStr>(name: &str, default: T) -> Result<T, String> {
+    match env::var(name) {
+        Ok(value) if !value.trim().is_empty() => value
+            .trim()
+            .parse()
+            .map_err(|_| format!("{} must be a number, got '{}'", name, value)),
+        _ => Ok(default),
+    }
+}
+
+impl RemoteEmbedder {
+    fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let url = env::var("EMBEDDING_URL").map_err(|_| {
+            "EMBEDDING_BACKEND=openai needs EMBEDDING_URL, e.g. http://localhost:8080/v1/embeddings"
+        })?;
+        let model = match env::var("EMBEDDING_MODEL") {
+            Ok(model) if !model.is_empty() => model,
+            _ => models::dense_model_name()?,
+        };
+        let batch_size = env_number("EMBEDDING_BATCH_SIZE", 32)?.max(1);
+        let timeout = env_number("EMBEDDING_TIMEOUT", 60)?;
+
+        // Default to the local model's prefixes, since the server should host the same model
+        let mut prefixes = models::dense_prefixes();
+        if let Ok(query) = env::var("EMBEDDING_QUERY_PREFIX") {
+            prefixes.query = query;
+        }
+        if let Ok(passage) = env::var("EMBEDDING_PASSAGE_PREFIX") {
+            prefixes.passage = passage;
+        }
+
+        Ok(Self {
+            client: Client::builder()
+                .timeout(Duration::from_secs(timeout))
+                .build()?,
+            url,
+            model,
+            api_key: env::var("EMBEDDING_API_KEY")
+                .ok()
+                .filter(|key| !key.is_empty()),
+            batch_size,
+            max_retries: env_number("EMBEDDING_MAX_RETRIES", 3)?,
+            prefixes,
+        })
+    }
+
+    /// Embed `inputs` in batches of `EMBEDDING_BATCH_SIZE`, keeping their order
+    async fn embed(
+        &self,
+        inputs: Vec<String>,
+    ) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error>> {
+        let mut embeddings = Vec::with_capacity(inputs.len());
+
+        for batch in inputs.chunks(self.batch_size) {
+            let start = Instant::now();
+            let mut data = self.send(batch).await?.data;
+            debug!(
+                "Embedded {} texts remotely: {:?}",
+                batch.len(),
+                start.elapsed()
+            );
+
+            if data.len() != batch.len() {
+                return Err(format!(
+                    "{} returned {} embeddings for {} inputs",
+                    self.url,
+                    data.len(),
+                    batch.len()
+                )
+                .into());
+            }
+            // The index, not the position in the response, says which input a vector is for
+            data.sort_by_key(|item| item.index);
+
+            for item in data {
+                if item.embedding.len() != DENSE_DIMENSIONS {
+                    return Err(DimensionMismatch {
+                        model: self.model.clone(),
+                        dimensions: item.embedding.len(),
+                    }
+                    .into());
+                }
+                embeddings.push(item.embedding);
+            }
+        }
+
+        Ok(embeddings)
+    }
+
+    /// Send one batch, retrying rate limits, server errors, timeouts and dropped connections
+    async fn send(
+        &self,
+        batch: &[String],
+    ) -> Result<EmbeddingResponse, Box<dyn std::error::Error>> {
+        let response = send_with_retries(
+            self.max_retries,
+            || {
+                let mut builder = self.client.post(&self.url).json(&EmbeddingRequest {
+                    model: &self.model,
+                    input: batch,
+                });
+                if let Some(api_key) = &self.api_key {
+                    builder = builder.bearer_auth(api_key);
+                }
+                Ok(builder)
+            },
+            |failure| match failure {
+                Ok((status, body)) => (
+                    format!(
+                        "Embedding request failed ({}): {}",
+                        status,
+                        error_message(&body)
+                    ),
+                    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error(),
+                ),
+                Err(e) => (
+                    format!("Embedding request to {} failed: {}", self.url, e),
+                    e.is_timeout() || e.is_connect(),
+                ),
+            },
+        )
+        .await?;
+
+        Ok(response.json().await?)
+    }
+}
@@ -0,0 +1,459 @@
+use crate::ai::{Expansion, LlmClient};
+use crate::cli_ui::{CliUI, EvalInfo};
+use crate::models::SearchModels;
+use crate::vector_store::{SearchMode, SearchOptions, SearchResult, VectorStore};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A labelled query, one per line of the eval file
+#[derive(Debug, Deserialize)]
+pub struct EvalQuery {
+    pub query: String,
+    pub relevant: Vec<RelevantItem>,
+}
+
+/// A file (or a single chunk of it) that a query is expected to retrieve
+#[derive(Debug, Deserialize)]
+pub struct RelevantItem {
+    pub file_path: String,
+    pub chunk_index: Option<i32>,
+}
+
+impl RelevantItem {
+    fn matches(&self, result: &SearchResult) -> bool {
+        result.file_path == self.file_path
+            && self
+                .chunk_index
+                .is_none_or(|chunk_index| chunk_index == result.chunk_index)
+    }
+}
+
+/// Mean retrieval metrics over every query for one search mode
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ModeMetrics {
+    pub recall: f64,
+    pub mrr: f64,
+    pub ndcg: f64,
+}
+
+/// A full eval run, saved as the baseline for later comparisons
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct EvalReport {
+    pub k: usize,
+    pub queries: usize,
+    pub modes: BTreeMap<String, ModeMetrics>,
+}
+
+/// What to evaluate and what to compare it against
+pub struct EvalConfig {
+    pub eval_file: PathBuf,
+    pub modes: Vec<SearchMode>,
+    pub options: SearchOptions,
+    /// LLM query expansion applied to every query before searching
+    pub expansion: Option<Expansion>,
+    pub baseline_file: Option<PathBuf>,
+    pub save_file: Option<PathBuf>,
+    /// Allowed drop in any metric before it counts as a regression
+    pub tolerance: f64,
+}
+
+fn mode_name(mode: SearchMode) -> String {
+    mode.to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .unwrap_or_default()
+}
+
+fn load_queries(eval_file: &Path) -> Result<Vec<EvalQuery>, Box<dyn std::error::Error>> {
+    let content = fs::read_to_string(eval_file)?;
+
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .map_err(|e| format!("{}:{}: {}", eval_file.display(), i + 1, e).into())
+        })
+        .collect()
+}
+
+/// Score one ranked result list. Each relevant item is credited once, at the rank of its
+/// first match, so several chunks from an expected file don't inflate the metrics.
+fn score_query(relevant: &[RelevantItem], results: &[SearchResult], k: usize) -> ModeMetrics {
+    if relevant.is_empty() {
+        return ModeMetrics::default();
+    }
+
+    let mut found = vec![false; relevant.len()];
+    let mut first_hit_rank = None;
+    let mut dcg = 0.0;
+
+    for (rank, result) in results.iter().take(k).enumerate() {
+        if !relevant.iter().any(|item| item.matches(result)) {
+            continue;
+        }
+        first_hit_rank.get_or_insert(rank + 1);
+
+        if let Some(i) = (0..relevant.len()).find(|&i| !found[i] && relevant[i].matches(result)) {
+            found[i] = true;
+            dcg += 1.0 / (rank as f64 + 2.0).log2();
+        }
+    }
+
+    let ideal_dcg = (0..relevant.len().min(k))
+        .map(|rank| 1.0 / (rank as f64 + 2.0).log2())
+        .sum::<f64>();
+
+    ModeMetrics {
+        recall: found.iter().filter(|&&f| f).count() as f64 / relevant.len() as f64,
+        mrr: first_hit_rank.map_or(0.0, |rank| 1.0 / rank as f64),
+        ndcg: if ideal_dcg > 0.0 {
+            dcg / ideal_dcg
+        } else {
+            0.0
+        },
+    }
+}
+
+/// Modes of this run that the baseline has no metrics for, e.g. because they were renamed
+fn modes_without_baseline<'a>(report: &'a EvalReport, baseline: &EvalReport) -> Vec<&'a str> {
+    report
+        .modes
+        .keys()
+        .filter(|mode| !baseline.modes.contains_key(*mode))
+        .map(String::as_str)
+        .collect()
+}
+
+/// Metrics that dropped by more than `tolerance` compared to the baseline. Modes the baseline
+/// lacks can't regress; see `modes_without_baseline`.
+fn find_regressions(report: &EvalReport, baseline: &EvalReport, tolerance: f64) -> Vec<String> {
+    let mut regressions = Vec::new();
+
+    for (mode, metrics) in &report.modes {
+        let Some(base) = baseline.modes.get(mode) else {
+            continue;
+        };
+
+        for (name, current, previous) in [
+            ("recall", metrics.recall, base.recall),
+            ("MRR", metrics.mrr, base.mrr),
+            ("nDCG", metrics.ndcg, base.ndcg),
+        ] {
+            if current < previous - tolerance {
+                regressions.push(format!(
+                    "{} {}@{}: {:.4} -> {:.4}",
+                    mode, name, report.k, previous, current
+                ));
+            }
+        }
+    }
+
+    regressions
+}
+
+pub async fn run(
+    vector_store: &impl VectorStore,
+    models: &mut SearchModels,
+    config: &EvalConfig,
+    ui: &CliUI,
+) -> Result<(), Box<dyn std::error::Error>> {
+    ui.print_section("Retrieval Evaluation");
+
+    let options = &config.options;
+    let queries = load_queries(&config.eval_file)?;
+    if queries.is_empty() {
+        return Err(format!("No queries found in {}", config.eval_file.display()).into());
+    }
+    ui.print_info(&format!(
+        "Evaluating {} queries at k = {}",
+        queries.len(),
+        options.top_k
+    ));
+
+    // Expand each query once so every mode searches the same variants
+    let mut extra_queries = Vec::with_capacity(queries.len());
+    if let Some(expansion) = &config.expansion {
+        let client = LlmClient::from_env()?;
+        let progress = ui.create_spinner("Expanding queries...");
+        for eval_query in &queries {
+            extra_queries.push(client.expand_query(&eval_query.query, expansion).await?);
+        }
+        progress.finish_and_clear();
+    } else {
+        extra_queries.resize(queries.len(), Vec::new());
+    }
+
+    let mut report = EvalReport {
+        k: options.top_k,
+        queries: queries.len(),
+        modes: BTreeMap::new(),
+    };
+
+    for &mode in &config.modes {
+        let mode_options = SearchOptions {
+            mode,
+            ..options.clone()
+        };
+        let progress = ui.create_spinner(&format!("Running {} search...", mode_name(mode)));
+
+        let mut totals = ModeMetrics::default();
+        for (eval_query, extra_queries) in queries.iter().zip(&extra_queries) {
+            let results = vector_store
+                .hybrid_search(&eval_query.query, extra_queries, models, &mode_options)
+                .await?;
+
+            let metrics = score_query(&eval_query.relevant, &results, options.top_k);
+            totals.recall += metrics.recall;
+            totals.mrr += metrics.mrr;
+            totals.ndcg += metrics.ndcg;
+        }
+        progress.finish_and_clear();
+
+        let count = queries.len() as f64;
+        report.modes.insert(
+            mode_name(mode),
+            ModeMetrics {
+                recall: totals.recall / count,
+                mrr: totals.mrr / count,
+                ndcg: totals.ndcg / count,
+            },
+        );
+    }
+
+    let baseline = match &config.baseline_file {
+        Some(path) => Some(serde_json::from_str::<EvalReport>(&fs::read_to_string(
+            path,
+        )?)?),
+        None => None,
+    };
+
+    let rows = report
+        .modes
+        .iter()
+        .map(|(mode, metrics)| {
+            let base = baseline.as_ref().and_then(|b| b.modes.get(mode));
+            EvalInfo {
+                mode: mode.clone(),
+                recall: format_metric(metrics.recall, base.map(|b| b.recall)),
+                mrr: format_metric(metrics.mrr, base.map(|b| b.mrr)),
+                ndcg: format_metric(metrics.ndcg, base.map(|b| b.ndcg)),
+            }
+        })
+        .collect::<Vec<_>>();
+    if ui.is_text() {
+        ui.print_eval_table(report.k, &rows);
+    }
+    ui.emit_ndjson(&report);
+    ui.emit_json(&report);
+
+    if let Some(path) = &config.save_file {
+        fs::write(path, serde_json::to_string_pretty(&report)?)?;
+        ui.print_success(&format!("Saved eval report to {}", path.display()));
+    }
+
+    if let Some(baseline) = baseline {
+        if baseline.k != report.k {
+            ui.print_warning(&format!(
+                "Baseline was measured at k = {}, this run used k = {}",
+                baseline.k, report.k
+            ));
+        }
+
+        for mode in modes_without_baseline(&report, &baseline) {
+            ui.print_warning(&format!(
+                "The baseline has no {} results, so that mode isn't checked for regressions",
+                mode
+            ));
+        }
+
+        let regressions = find_regressions(&report, &baseline, config.tolerance);
+        if !regressions.is_empty() {
+            for regression in &regressions {
+                ui.print_error(regression);
+            }
+            return Err(format!(
+                "{} metrics regressed against the baseline",
+                regressions.len()
+            )
+            .into());
+        }
+        ui.print_success("No regressions against the baseline");
+    }
+
+    Ok(())
+}
+
+fn format_metric(value: f64, baseline: Option<f64>) -> String {
+    match baseline {
+        Some(baseline) => format!("{:.4} ({:+.4})", value, value - baseline),
+        None => format!("{:.4}", value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relevant(items: &[(&str, Option<i32>)]) -> Vec<RelevantItem> {
+        items
+            .iter()
+            .map(|&(file_path, chunk_index)| RelevantItem {
+                file_path: file_path.to_string(),
+                chunk_index,
+            })
+            .collect()
+    }
+
+    fn results(items: &[(&str, i32)]) -> Vec<SearchResult> {
+        items
+            .iter()
+            .map(|&(file_path, chunk_index)| {
+                SearchResult::new(file_path.to_string(), String::new(), chunk_index, 0.0)
+            })
+            .collect()
+    }
+
+    fn metrics(recall: f64, mrr: f64, ndcg: f64) -> ModeMetrics {
+        ModeMetrics { recall, mrr, ndcg }
+    }
+
+    /// A name, the relevant items, the ranked results, k and the expected metrics
+    type Case = (
+        &'static str,
+        &'static [(&'static str, Option<i32>)],
+        &'static [(&'static str, i32)],
+        usize,
+        ModeMetrics,
+    );
+
+    #[test]
+    fn score_query_metrics() {
+        // 1 / log2(3), the discount of a hit at rank 2
+        let second = 1.0 / 3f64.log2();
+
+        let cases: &[Case] = &[
+            (
+                "hit at rank 1",
+                &[("a.md", None)],
+                &[("a.md", 0)],
+                10,
+                metrics(1.0, 1.0, 1.0),
+            ),
+            (
+                "hit at rank 2",
+                &[("a.md", None)],
+                &[("b.md", 0), ("a.md", 0)],
+                10,
+                metrics(1.0, 0.5, second),
+            ),
+            (
+                "more chunks of a found file aren't credited again",
+                &[("a.md", None), ("b.md", None)],
+                &[("a.md", 0), ("a.md", 1), ("b.md", 0)],
+                10,
+                metrics(1.0, 1.0, 1.5 / (1.0 + second)),
+            ),
+            (
+                "only the expected chunk counts",
+                &[("a.md", Some(2))],
+                &[("a.md", 0), ("a.md", 2)],
+                10,
+                metrics(1.0, 0.5, second),
+            ),
+            (
+                "hits below k are ignored",
+                &[("a.md", None)],
+                &[("b.md", 0), ("a.md", 0)],
+                1,
+                metrics(0.0, 0.0, 0.0),
+            ),
+            (
+                "ideal DCG is limited to k results",
+                &[("a.md", None), ("b.md", None)],
+                &[("a.md", 0)],
+                1,
+                metrics(0.5, 1.0, 1.0),
+            ),
+            (
+                "nothing found",
+                &[("a.md", None)],
+                &[],
+                10,
+                metrics(0.0, 0.0, 0.0),
+            ),
+            (
+                "nothing relevant",
+                &[],
+                &[("a.md", 0)],
+                10,
+                metrics(0.0, 0.0, 0.0),
+            ),
+        ];
+
+        for (name, relevant_items, ranked, k, expected) in cases {
+            let actual = score_query(&relevant(relevant_items), &results(ranked), *k);
+            for (metric, actual, expected) in [
+                ("recall", actual.recall, expected.recall),
+                ("MRR", actual.mrr, expected.mrr),
+                ("nDCG", actual.ndcg, expected.ndcg),
+            ] {
+                assert!(
+                    (actual - expected).abs() < 1e-9,
+                    "{}: {} is {}, expected {}",
+                    name,
+                    metric,
+                    actual,
+                    expected
+                );
+            }
+        }
+    }
+
+    fn report(modes: &[(&str, ModeMetrics)]) -> EvalReport {
+        EvalReport {
+            k: 10,
+            queries: 5,
+            modes: modes
+                .iter()
+                .map(|(mode, metrics)| (mode.to_string(), metrics.clone()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn regressions_beyond_tolerance() {
+        let baseline = report(&[
+            ("dense", metrics(0.8, 0.6, 0.7)),
+            ("full", metrics(0.9, 0.8, 0.85)),
+        ]);
+        let current = report(&[
+            ("dense", metrics(0.78, 0.6, 0.7)),
+            ("full", metrics(0.9, 0.7, 0.9)),
+        ]);
+
+        assert_eq!(
+            find_regressions(&current, &baseline, 0.05),
+            ["full MRR@10: 0.8000 -> 0.7000"]
+        );
+        assert_eq!(
+            find_regressions(&current, &baseline, 0.2),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn modes_missing_from_the_baseline() {
+        let baseline = report(&[("full", metrics(0.9, 0.8, 0.85))]);
+        let current = report(&[
+            ("full", metrics(0.9, 0.8, 0.85)),
+            ("hybrid", metrics(0.1, 0.1, 0.1)),
+        ]);
+
+        assert_eq!(modes_without_baseline(&current, &baseline), ["hybrid"]);
+        assert!(find_regressions(&current, &baseline, 0.0).is_empty());
+    }
+}
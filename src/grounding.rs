@@ -0,0 +1,101 @@
+use crate::vector_store::SearchResult;
+use regex::Regex;
+use std::collections::HashSet;
+
+/// Share of a sentence's content words that one source must contain for it to count as supported
+const MIN_OVERLAP: f64 = 0.5;
+
+/// Sentences with fewer content words than this (transitions, "I hope this helps") aren't checked
+const MIN_WORDS: usize = 4;
+
+/// Common words that say nothing about whether a sentence came from the sources
+const STOP_WORDS: &str = "\
+    about above after again also and any are because been before being between both but can \
+    could did does doing during each for from had has have having her here his how however \
+    into its just may more most must not now only other our over should some such than that \
+    the their them then there these they this those through under very was were what when \
+    where which while who why will with would you your source sources document documents \
+    according based provided information mentioned";
+
+/// Whether the best result scores at least `threshold`, i.e. the documents are worth answering from
+pub fn is_relevant(results: &[SearchResult], threshold: f32) -> bool {
+    results
+        .iter()
+        .any(|result| result.similarity_score >= threshold as f64)
+}
+
+/// Lowercased content words, with a trailing plural "s" dropped so "towers" matches "tower"
+fn content_words(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .map(|word| word.to_lowercase())
+        .filter(|word| {
+            word.chars().count() >= 3 && !STOP_WORDS.split_whitespace().any(|stop| stop == word)
+        })
+        .map(|word| match word.strip_suffix('s') {
+            Some(stem) if stem.len() >= 3 && !stem.ends_with('s') => stem.to_string(),
+            _ => word,
+        })
+        .collect()
+}
+
+/// Split a Markdown answer into sentences, dropping list markers, headings and code blocks
+fn sentences(answer: &str) -> Vec<String> {
+    let citation = Regex::new(r"\[Source \d+(, *\d+)*\]").unwrap();
+    let marker = Regex::new(r"^\s*([#>|*+-]+|\d+[.)])\s*").unwrap();
+
+    let mut sentences = Vec::new();
+    let mut in_code = false;
+    for line in answer.lines() {
+        if line.trim_start().starts_with("```") {
+            in_code = !in_code;
+            continue;
+        }
+        if in_code {
+            continue;
+        }
+
+        let line = citation.replace_all(line, "");
+        let line = marker.replace(&line, "");
+        let mut sentence = String::new();
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            sentence.push(c);
+            // "3.5" and "e.g.," stay together; a sentence ends at punctuation before a space
+            if matches!(c, '.' | '!' | '?') && chars.peek().is_none_or(|next| next.is_whitespace())
+            {
+                sentences.push(std::mem::take(&mut sentence));
+            }
+        }
+        sentences.push(sentence);
+    }
+
+    sentences
+        .into_iter()
+        .map(|sentence| sentence.trim().to_string())
+        .filter(|sentence| !sentence.is_empty())
+        .collect()
+}
+
+/// Sentences of the answer that no single source supports: less than half of their content
+/// words appear in any one source. A lexical check, so it flags likely additions from the
+/// model's own knowledge rather than proving the remaining sentences are correct.
+pub fn unsupported_sentences(answer: &str, sources: &[SearchResult]) -> Vec<String> {
+    let sources = sources
+        .iter()
+        .map(|source| content_words(&source.chunk_content))
+        .collect::<Vec<_>>();
+
+    sentences(answer)
+        .into_iter()
+        .filter(|sentence| {
+            let words = content_words(sentence);
+            if words.len() < MIN_WORDS {
+                return false;
+            }
+            !sources.iter().any(|source| {
+                let found = words.iter().filter(|word| source.contains(*word)).count();
+                found as f64 / words.len() as f64 >= MIN_OVERLAP
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,867 @@
+use crate::models::SearchModels;
+use crate::vector_store::{
+    embed_dense_query, embed_sparse_query, file_id_for_path, in_path, ChunkMetadata, FileMetadata,
+    FusionMethod, IndexedFile, SearchFilter, SearchMode, SearchOptions, SearchResult, VectorStore,
+    DENSE_DIMENSIONS,
+};
+use fastembed::SparseEmbedding;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Constant from the original Reciprocal Rank Fusion paper, damping the weight of top ranks
+const RRF_K: f32 = 60.0;
+
+/// A chunk as written to its document's `.jsonl` file
+#[derive(Serialize, Deserialize)]
+struct StoredChunk {
+    #[serde(flatten)]
+    metadata: ChunkMetadata,
+    dense: Vec<f32>,
+    sparse_indices: Vec<u32>,
+    sparse_values: Vec<f32>,
+}
+
+/// A chunk in memory, with its dense vector normalized so a dot product is the cosine
+struct Chunk {
+    metadata: ChunkMetadata,
+    dense: Vec<f32>,
+    sparse: Vec<(u32, f32)>,
+}
+
+impl From<StoredChunk> for Chunk {
+    fn from(stored: StoredChunk) -> Self {
+        Self {
+            metadata: stored.metadata,
+            dense: normalized(stored.dense),
+            sparse: stored
+                .sparse_indices
+                .into_iter()
+                .zip(stored.sparse_values)
+                .collect(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Index {
+    /// Document metadata by file ID, without the markdown, which is read from disk on demand
+    files: BTreeMap<String, FileMetadata>,
+    chunks: Vec<Chunk>,
+    /// Sparse term to the chunks containing it and the term's weight in each
+    postings: HashMap<u32, Vec<(usize, f32)>>,
+}
+
+impl Index {
+    fn add_chunk(&mut self, chunk: Chunk) {
+        let position = self.chunks.len();
+        for &(term, weight) in &chunk.sparse {
+            self.postings
+                .entry(term)
+                .or_default()
+                .push((position, weight));
+        }
+        self.chunks.push(chunk);
+    }
+
+    /// Drop a document's chunks, e.g. before it is indexed again
+    fn remove_chunks(&mut self, file_id: &str) {
+        if !self.chunks.iter().any(|c| c.metadata.file_id == file_id) {
+            return;
+        }
+
+        let chunks = std::mem::take(&mut self.chunks);
+        self.postings.clear();
+        for chunk in chunks {
+            if chunk.metadata.file_id != file_id {
+                self.add_chunk(chunk);
+            }
+        }
+    }
+
+    /// The best `limit` chunks by cosine similarity to `query`
+    fn search_dense(&self, query: &[f32], filter: &SearchFilter, limit: u64) -> Vec<(usize, f32)> {
+        let query = normalized(query.to_vec());
+        let scores = self
+            .chunks
+            .iter()
+            .enumerate()
+            .filter(|(_, chunk)| matches_filter(filter, &chunk.metadata))
+            .map(|(i, chunk)| (i, dot(&query, &chunk.dense)))
+            .collect();
+        top(scores, limit)
+    }
+
+    /// The best `limit` chunks by dot product with the sparse `query`, like Qdrant's sparse search
+    fn search_sparse(
+        &self,
+        query: &SparseEmbedding,
+        filter: &SearchFilter,
+        limit: u64,
+    ) -> Vec<(usize, f32)> {
+        let mut scores = HashMap::new();
+        for (&term, &query_weight) in query.indices.iter().zip(&query.values) {
+            for &(position, weight) in self.postings.get(&(term as u32)).into_iter().flatten() {
+                *scores.entry(position).or_insert(0.0) += query_weight * weight;
+            }
+        }
+
+        let scores = scores
+            .into_iter()
+            .filter(|&(i, _)| matches_filter(filter, &self.chunks[i].metadata))
+            .collect();
+        top(scores, limit)
+    }
+
+    fn search_result(&self, (position, score): (usize, f32)) -> SearchResult {
+        let chunk = &self.chunks[position].metadata;
+        SearchResult::new(
+            chunk.file_path.clone(),
+            chunk.chunk_content.clone(),
+            chunk.chunk_index,
+            score as f64,
+        )
+    }
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+fn normalized(mut vector: Vec<f32>) -> Vec<f32> {
+    let norm = dot(&vector, &vector).sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|value| *value /= norm);
+    }
+    vector
+}
+
+/// The `limit` highest scores, best first
+fn top(mut scores: Vec<(usize, f32)>, limit: u64) -> Vec<(usize, f32)> {
+    scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+    scores.truncate(limit as usize);
+    scores
+}
+
+/// The same conditions the Qdrant store expresses as payload filters
+fn matches_filter(filter: &SearchFilter, chunk: &ChunkMetadata) -> bool {
+    let prefix_matches = filter
+        .path_prefix
+        .as_ref()
+        .is_none_or(|prefix| in_path(&chunk.file_path, prefix));
+    let extension_matches = filter.extensions.is_empty()
+        || filter.extensions.iter().any(|ext| {
+            ext.trim_start_matches('.')
+                .eq_ignore_ascii_case(&chunk.file_extension)
+        });
+    let time_matches = filter
+        .modified_after
+        .is_none_or(|after| chunk.modified_time >= after)
+        && filter
+            .modified_before
+            .is_none_or(|before| chunk.modified_time < before);
+
+    prefix_matches && extension_matches && time_matches
+}
+
+/// Combine ranked candidate lists into one, best first
+fn fuse(lists: Vec<Vec<(usize, f32)>>, method: FusionMethod, limit: u64) -> Vec<(usize, f32)> {
+    let mut fused = HashMap::new();
+
+    for list in lists {
+        match method {
+            FusionMethod::Rrf => {
+                for (rank, (position, _)) in list.into_iter().enumerate() {
+                    *fused.entry(position).or_insert(0.0) += 1.0 / (RRF_K + rank as f32 + 1.0);
+                }
+            }
+            // Scale each list's scores by its mean +/- 3 standard deviations, then sum
+            FusionMethod::Dbsf => {
+                let count = list.len() as f32;
+                let mean = list.iter().map(|(_, score)| score).sum::<f32>() / count;
+                let variance = list
+                    .iter()
+                    .map(|(_, score)| (score - mean).powi(2))
+                    .sum::<f32>()
+                    / count;
+                let (low, high) = (mean - 3.0 * variance.sqrt(), mean + 3.0 * variance.sqrt());
+                for (position, score) in list {
+                    let normalized = if high > low {
+                        ((score - low) / (high - low)).clamp(0.0, 1.0)
+                    } else {
+                        0.5
+                    };
+                    *fused.entry(position).or_insert(0.0) += normalized;
+                }
+            }
+        }
+    }
+
+    top(fused.into_iter().collect(), limit)
+}
+
+/// The chunks in a `.jsonl` file
+fn read_chunks(path: &Path) -> Result<Vec<StoredChunk>, Box<dyn std::error::Error>> {
+    let mut chunks = Vec::new();
+    for line in BufReader::new(fs::File::open(path)?).lines() {
+        // A run killed mid-write can leave a truncated last line; skip it
+        match serde_json::from_str::<StoredChunk>(&line?) {
+            Ok(chunk) => chunks.push(chunk),
+            Err(e) => warn!("Skipping an unreadable chunk in {}: {}", path.display(), e),
+        }
+    }
+    Ok(chunks)
+}
+
+/// The per-user data directory, so every working directory searches the same index
+fn default_store_dir() -> PathBuf {
+    let base = env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("LOCALAPPDATA").map(PathBuf::from))
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local").join("share")));
+
+    match base {
+        Some(base) => base.join("ssc").join("index"),
+        None => PathBuf::from("ssc-index"),
+    }
+}
+
+/// `LOCAL_STORE_DIR`, by default `~/.local/share/ssc/index`
+pub fn store_dir_from_env() -> PathBuf {
+    env::var_os("LOCAL_STORE_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(default_store_dir)
+}
+
+/// Documents and their chunk embeddings in a directory, for indexing and searching without a
+/// Qdrant server. Each document is a `files/<id>.json` and `files/<id>.md`, its chunks one
+/// line each in `chunks/<id>.jsonl`; everything but the markdown is loaded into memory and
+/// searched exhaustively, which is fast enough for a personal document collection.
+pub struct LocalVectorStore {
+    dir: PathBuf,
+    index: RefCell<Index>,
+}
+
+impl LocalVectorStore {
+    pub fn open(dir: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let start = Instant::now();
+        fs::create_dir_all(dir.join("files"))?;
+        fs::create_dir_all(dir.join("chunks"))?;
+
+        let mut index = Index::default();
+        for entry in fs::read_dir(dir.join("files"))? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                let file: FileMetadata = serde_json::from_str(&fs::read_to_string(&path)?)
+                    .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+                index.files.insert(file_id_for_path(&file.file_path), file);
+            }
+        }
+
+        for entry in fs::read_dir(dir.join("chunks"))? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "jsonl") {
+                continue;
+            }
+            for chunk in read_chunks(&path)? {
+                index.add_chunk(chunk.into());
+            }
+        }
+
+        info!(
+            "Opened the local index in {} with {} documents and {} chunks",
+            dir.display(),
+            index.files.len(),
+            index.chunks.len()
+        );
+        debug!("Loading the local index: {:?}", start.elapsed());
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            index: RefCell::new(index),
+        })
+    }
+
+    fn file_path(&self, file_id: &str, extension: &str) -> PathBuf {
+        self.dir
+            .join("files")
+            .join(format!("{}.{}", file_id, extension))
+    }
+
+    fn chunks_path(&self, file_id: &str) -> PathBuf {
+        self.dir.join("chunks").join(format!("{}.jsonl", file_id))
+    }
+
+    /// Chunks of a document being indexed, not loaded by `open`
+    fn pending_chunks_path(&self, file_id: &str) -> PathBuf {
+        self.dir
+            .join("chunks")
+            .join(format!("{}.jsonl.pending", file_id))
+    }
+}
+
+impl VectorStore for LocalVectorStore {
+    async fn store_file_metadata(
+        &self,
+        file: &FileMetadata,
+        chunk_count: usize,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let file_id = file_id_for_path(&file.file_path);
+        let pending_path = self.pending_chunks_path(&file_id);
+        let chunks = if chunk_count == 0 {
+            Vec::new()
+        } else {
+            read_chunks(&pending_path)?
+        };
+        if chunks.len() != chunk_count {
+            return Err(format!(
+                "Expected {} stored chunks for {}, found {}",
+                chunk_count,
+                file.file_path,
+                chunks.len()
+            )
+            .into());
+        }
+
+        // The markdown is kept in its own file
+        let metadata = FileMetadata {
+            markdown_content: None,
+            ..file.clone()
+        };
+
+        // Swap in the new chunks, then the metadata with the new hash
+        if chunk_count == 0 {
+            match fs::remove_file(self.chunks_path(&file_id)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        } else {
+            fs::rename(&pending_path, self.chunks_path(&file_id))?;
+        }
+        fs::write(
+            self.file_path(&file_id, "md"),
+            file.markdown_content.as_deref().unwrap_or_default(),
+        )?;
+        fs::write(
+            self.file_path(&file_id, "json"),
+            serde_json::to_string_pretty(&metadata)?,
+        )?;
+
+        let mut index = self.index.borrow_mut();
+        index.remove_chunks(&file_id);
+        for chunk in chunks {
+            index.add_chunk(chunk.into());
+        }
+        index.files.insert(file_id.clone(), metadata);
+
+        Ok(file_id)
+    }
+
+    async fn store_embeddings(
+        &self,
+        chunk: &ChunkMetadata,
+        dense_embedding: &[f32],
+        sparse_embedding: &SparseEmbedding,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if dense_embedding.len() != DENSE_DIMENSIONS {
+            return Err(format!(
+                "Expected a {}-dimensional dense embedding, got {}",
+                DENSE_DIMENSIONS,
+                dense_embedding.len()
+            )
+            .into());
+        }
+
+        let stored = StoredChunk {
+            metadata: chunk.clone(),
+            dense: dense_embedding.to_vec(),
+            sparse_indices: sparse_embedding.indices.iter().map(|&i| i as u32).collect(),
+            sparse_values: sparse_embedding.values.clone(),
+        };
+
+        // Collected next to the current chunks, which stay searchable until the document's
+        // metadata is stored; the first chunk discards whatever a failed run left behind
+        let pending_path = self.pending_chunks_path(&chunk.file_id);
+        let mut file = if chunk.chunk_index == 0 {
+            fs::File::create(&pending_path)?
+        } else {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&pending_path)?
+        };
+        writeln!(file, "{}", serde_json::to_string(&stored)?)?;
+
+        Ok(())
+    }
+
+    async fn list_files(&self) -> Result<Vec<IndexedFile>, Box<dyn std::error::Error>> {
+        let index = self.index.borrow();
+
+        let mut chunk_counts = HashMap::new();
+        for chunk in &index.chunks {
+            *chunk_counts
+                .entry(chunk.metadata.file_id.as_str())
+                .or_insert(0) += 1;
+        }
+
+        Ok(index
+            .files
+            .iter()
+            .map(|(file_id, file)| IndexedFile {
+                file_path: file.file_path.clone(),
+                file_size: file.file_size,
+                modified_time: file.modified_time,
+                content_hash: file.content_hash.clone(),
+                chunk_count: chunk_counts.get(file_id.as_str()).copied().unwrap_or(0),
+            })
+            .collect())
+    }
+
+    async fn get_file(
+        &self,
+        file_path: &str,
+    ) -> Result<Option<FileMetadata>, Box<dyn std::error::Error>> {
+        let file_id = file_id_for_path(file_path);
+        let Some(file) = self.index.borrow().files.get(&file_id).cloned() else {
+            return Ok(None);
+        };
+
+        Ok(Some(FileMetadata {
+            markdown_content: fs::read_to_string(self.file_path(&file_id, "md")).ok(),
+            ..file
+        }))
+    }
+
+    async fn retrieve(
+        &self,
+        queries: &[&str],
+        models: &mut SearchModels,
+        options: &SearchOptions,
+    ) -> Result<Vec<SearchResult>, Box<dyn std::error::Error>> {
+        // Embed first, so the index isn't borrowed across an await
+        let mut dense_queries = Vec::new();
+        let mut sparse_queries = Vec::new();
+        for query in queries {
+            if options.mode != SearchMode::Sparse {
+                dense_queries.push(embed_dense_query(query, models.dense.get()?).await?);
+            }
+            if options.mode != SearchMode::Dense {
+                sparse_queries.push(embed_sparse_query(query, models.sparse.get()?)?);
+            }
+        }
+
+        let search_start = Instant::now();
+        let index = self.index.borrow();
+        let filter = &options.filter;
+
+        let candidates = match (options.mode, queries) {
+            (SearchMode::Dense, [_]) => {
+                index.search_dense(&dense_queries[0], filter, options.top_k as u64)
+            }
+            (SearchMode::Sparse, [_]) => {
+                index.search_sparse(&sparse_queries[0], filter, options.top_k as u64)
+            }
+            _ => {
+                // One candidate list per query and branch, all fused into one
+                let lists = sparse_queries
+                    .iter()
+                    .map(|query| index.search_sparse(query, filter, options.sparse_limit))
+                    .chain(
+                        dense_queries
+                            .iter()
+                            .map(|query| index.search_dense(query, filter, options.dense_limit)),
+                    )
+                    .collect();
+                fuse(lists, options.fusion, options.retrieve_limit())
+            }
+        };
+        debug!("Local index search: {:?}", search_start.elapsed());
+
+        Ok(candidates
+            .into_iter()
+            .map(|candidate| index.search_result(candidate))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty store in a fresh temporary directory
+    fn temp_store(name: &str) -> (PathBuf, LocalVectorStore) {
+        let dir = env::temp_dir().join(format!("ssc-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let store = LocalVectorStore::open(&dir).unwrap();
+        (dir, store)
+    }
+
+    fn file(file_path: &str, content_hash: &str) -> FileMetadata {
+        FileMetadata {
+            file_path: file_path.to_string(),
+            file_name: file_path.to_string(),
+            file_size: 1,
+            modified_time: 0,
+            content_hash: content_hash.to_string(),
+            markdown_content: Some(String::new()),
+        }
+    }
+
+    async fn store_chunk(store: &LocalVectorStore, file_path: &str, chunk_index: i32, text: &str) {
+        let chunk = ChunkMetadata {
+            file_id: file_id_for_path(file_path),
+            file_path: file_path.to_string(),
+            file_extension: "md".to_string(),
+            modified_time: 0,
+            chunk_index,
+            chunk_content: text.to_string(),
+        };
+        let sparse = SparseEmbedding {
+            indices: vec![1],
+            values: vec![1.0],
+        };
+        store
+            .store_embeddings(&chunk, &[1.0; DENSE_DIMENSIONS], &sparse)
+            .await
+            .unwrap();
+    }
+
+    fn chunk_contents(store: &LocalVectorStore) -> Vec<String> {
+        let index = store.index.borrow();
+        index
+            .chunks
+            .iter()
+            .map(|chunk| chunk.metadata.chunk_content.clone())
+            .collect()
+    }
+
+    fn indexed_chunk(
+        file_path: &str,
+        modified_time: u64,
+        dense: Vec<f32>,
+        sparse: Vec<(u32, f32)>,
+    ) -> Chunk {
+        Chunk {
+            metadata: ChunkMetadata {
+                file_id: file_id_for_path(file_path),
+                file_path: file_path.to_string(),
+                file_extension: Path::new(file_path)
+                    .extension()
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string(),
+                modified_time,
+                chunk_index: 0,
+                chunk_content: String::new(),
+            },
+            dense: normalized(dense),
+            sparse,
+        }
+    }
+
+    fn sample_index() -> Index {
+        let mut index = Index::default();
+        index.add_chunk(indexed_chunk(
+            "docs/a.md",
+            100,
+            vec![1.0, 0.0],
+            vec![(1, 1.0)],
+        ));
+        index.add_chunk(indexed_chunk(
+            "docs-old/b.pdf",
+            200,
+            vec![0.9, 0.1],
+            vec![(1, 0.5), (2, 1.0)],
+        ));
+        index.add_chunk(indexed_chunk(
+            "notes/c.md",
+            300,
+            vec![0.0, 1.0],
+            vec![(2, 2.0)],
+        ));
+        index
+    }
+
+    fn paths(index: &Index, results: Vec<(usize, f32)>) -> Vec<String> {
+        results
+            .into_iter()
+            .map(|result| index.search_result(result).file_path)
+            .collect()
+    }
+
+    fn filter(
+        path_prefix: Option<&str>,
+        extensions: &[&str],
+        modified_after: Option<u64>,
+        modified_before: Option<u64>,
+    ) -> SearchFilter {
+        SearchFilter {
+            path_prefix: path_prefix.map(str::to_string),
+            extensions: extensions.iter().map(|ext| ext.to_string()).collect(),
+            modified_after,
+            modified_before,
+        }
+    }
+
+    #[test]
+    fn dense_search_with_filters() {
+        let index = sample_index();
+        let query = [1.0, 0.0];
+
+        for (name, filter, expected) in [
+            (
+                "no filter",
+                SearchFilter::default(),
+                vec!["docs/a.md", "docs-old/b.pdf", "notes/c.md"],
+            ),
+            (
+                "path",
+                filter(Some("docs/"), &[], None, None),
+                vec!["docs/a.md"],
+            ),
+            (
+                "root path",
+                filter(Some("/"), &[], None, None),
+                vec!["docs/a.md", "docs-old/b.pdf", "notes/c.md"],
+            ),
+            (
+                "extension",
+                filter(None, &[".PDF"], None, None),
+                vec!["docs-old/b.pdf"],
+            ),
+            (
+                "after",
+                filter(None, &[], Some(200), None),
+                vec!["docs-old/b.pdf", "notes/c.md"],
+            ),
+            (
+                "before",
+                filter(None, &[], None, Some(200)),
+                vec!["docs/a.md"],
+            ),
+            (
+                "combined",
+                filter(Some("notes"), &["md"], Some(250), Some(400)),
+                vec!["notes/c.md"],
+            ),
+        ] {
+            assert_eq!(
+                paths(&index, index.search_dense(&query, &filter, 10)),
+                expected,
+                "{}",
+                name
+            );
+        }
+        assert_eq!(
+            paths(
+                &index,
+                index.search_dense(&query, &SearchFilter::default(), 1)
+            ),
+            ["docs/a.md"]
+        );
+    }
+
+    #[test]
+    fn sparse_search_with_filters() {
+        let index = sample_index();
+        let query = |term: usize| SparseEmbedding {
+            indices: vec![term],
+            values: vec![1.0],
+        };
+
+        for (name, term, filter, expected) in [
+            (
+                "term 1",
+                1,
+                SearchFilter::default(),
+                vec!["docs/a.md", "docs-old/b.pdf"],
+            ),
+            (
+                "term 2",
+                2,
+                SearchFilter::default(),
+                vec!["notes/c.md", "docs-old/b.pdf"],
+            ),
+            ("unknown term", 3, SearchFilter::default(), vec![]),
+            (
+                "path",
+                1,
+                filter(Some("docs"), &[], None, None),
+                vec!["docs/a.md"],
+            ),
+            (
+                "extension",
+                1,
+                filter(None, &["md"], None, None),
+                vec!["docs/a.md"],
+            ),
+            (
+                "after",
+                2,
+                filter(None, &[], Some(250), None),
+                vec!["notes/c.md"],
+            ),
+            (
+                "before",
+                2,
+                filter(None, &[], None, Some(250)),
+                vec!["docs-old/b.pdf"],
+            ),
+        ] {
+            assert_eq!(
+                paths(&index, index.search_sparse(&query(term), &filter, 10)),
+                expected,
+                "{}",
+                name
+            );
+        }
+    }
+
+    /// Every posting points at a chunk with that term and weight, and every term of every
+    /// chunk has a posting
+    fn assert_postings_consistent(index: &Index) {
+        let mut postings = 0;
+        for (term, list) in &index.postings {
+            for &(position, weight) in list {
+                assert!(index.chunks[position].sparse.contains(&(*term, weight)));
+            }
+            postings += list.len();
+        }
+        let terms = index
+            .chunks
+            .iter()
+            .map(|chunk| chunk.sparse.len())
+            .sum::<usize>();
+        assert_eq!(postings, terms);
+    }
+
+    #[test]
+    fn remove_chunks_keeps_postings_consistent() {
+        let mut index = sample_index();
+        assert_postings_consistent(&index);
+
+        index.remove_chunks(&file_id_for_path("docs/a.md"));
+        assert_postings_consistent(&index);
+        assert_eq!(index.chunks.len(), 2);
+        let term_1 = SparseEmbedding {
+            indices: vec![1],
+            values: vec![1.0],
+        };
+        let none = SearchFilter::default();
+        assert_eq!(
+            paths(&index, index.search_sparse(&term_1, &none, 10)),
+            ["docs-old/b.pdf"]
+        );
+
+        // Indexed again with different terms
+        index.add_chunk(indexed_chunk(
+            "docs/a.md",
+            400,
+            vec![1.0, 1.0],
+            vec![(3, 1.0)],
+        ));
+        assert_postings_consistent(&index);
+        assert_eq!(
+            paths(&index, index.search_sparse(&term_1, &none, 10)),
+            ["docs-old/b.pdf"]
+        );
+
+        index.remove_chunks(&file_id_for_path("missing.md"));
+        assert_postings_consistent(&index);
+        assert_eq!(index.chunks.len(), 3);
+    }
+
+    fn positions(results: Vec<(usize, f32)>) -> Vec<usize> {
+        results.into_iter().map(|(position, _)| position).collect()
+    }
+
+    #[test]
+    fn reciprocal_rank_fusion() {
+        let fused = fuse(
+            vec![vec![(0, 0.9), (1, 0.5)], vec![(1, 10.0), (2, 5.0)]],
+            FusionMethod::Rrf,
+            10,
+        );
+        assert_eq!(positions(fused.clone()), [1, 0, 2]);
+        assert!((fused[0].1 - (1.0 / 62.0 + 1.0 / 61.0)).abs() < 1e-6);
+        assert!((fused[1].1 - 1.0 / 61.0).abs() < 1e-6);
+
+        // Only the rank counts, not the score
+        let one_sided = fuse(
+            vec![vec![], vec![(4, 0.1), (3, 0.05)]],
+            FusionMethod::Rrf,
+            10,
+        );
+        assert_eq!(positions(one_sided), [4, 3]);
+        assert!(fuse(vec![], FusionMethod::Rrf, 10).is_empty());
+        assert!(fuse(vec![vec![], vec![]], FusionMethod::Rrf, 10).is_empty());
+        assert_eq!(
+            positions(fuse(vec![vec![(0, 1.0), (1, 0.5)]], FusionMethod::Rrf, 1)),
+            [0]
+        );
+    }
+
+    #[test]
+    fn distribution_based_score_fusion() {
+        // Scores 1 and 0 have mean 0.5 and deviation 0.5, so they scale to 2/3 and 1/3 of
+        // [-1, 2]; a single score has no spread and counts as 0.5
+        let fused = fuse(
+            vec![vec![(0, 1.0), (1, 0.0)], vec![(1, 4.0)]],
+            FusionMethod::Dbsf,
+            10,
+        );
+        assert_eq!(positions(fused.clone()), [1, 0]);
+        assert!((fused[0].1 - (1.0 / 3.0 + 0.5)).abs() < 1e-6);
+        assert!((fused[1].1 - 2.0 / 3.0).abs() < 1e-6);
+
+        let one_sided = fuse(
+            vec![vec![], vec![(3, 0.2), (4, 0.1)]],
+            FusionMethod::Dbsf,
+            10,
+        );
+        assert_eq!(positions(one_sided), [3, 4]);
+        assert!(fuse(vec![], FusionMethod::Dbsf, 10).is_empty());
+        assert!(fuse(vec![vec![], vec![]], FusionMethod::Dbsf, 10).is_empty());
+    }
+
+    #[tokio::test]
+    async fn reindex_keeps_previous_version_until_metadata_is_stored() {
+        let (dir, store) = temp_store("reindex");
+        store_chunk(&store, "a.md", 0, "old 0").await;
+        store_chunk(&store, "a.md", 1, "old 1").await;
+        store
+            .store_file_metadata(&file("a.md", "v1"), 2)
+            .await
+            .unwrap();
+
+        // A run that fails after the first chunk of the new version
+        store_chunk(&store, "a.md", 0, "new 0").await;
+        assert_eq!(chunk_contents(&store), ["old 0", "old 1"]);
+        let store = LocalVectorStore::open(&dir).unwrap();
+        assert_eq!(chunk_contents(&store), ["old 0", "old 1"]);
+        assert_eq!(store.list_files().await.unwrap()[0].content_hash, "v1");
+
+        store_chunk(&store, "a.md", 0, "new 0").await;
+        store
+            .store_file_metadata(&file("a.md", "v2"), 1)
+            .await
+            .unwrap();
+        assert_eq!(chunk_contents(&store), ["new 0"]);
+        let store = LocalVectorStore::open(&dir).unwrap();
+        assert_eq!(chunk_contents(&store), ["new 0"]);
+        let files = store.list_files().await.unwrap();
+        assert_eq!(
+            (files[0].content_hash.as_str(), files[0].chunk_count),
+            ("v2", 1)
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
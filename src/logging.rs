@@ -0,0 +1,143 @@
+use colored::*;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde_json::json;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Logger writing human-readable lines to stderr and, optionally, JSON lines to a file
+struct Logger {
+    default_level: LevelFilter,
+    /// `RUST_LOG`-style `target=level` overrides, matched by target prefix
+    directives: Vec<(String, LevelFilter)>,
+    file: Option<Mutex<File>>,
+}
+
+impl Logger {
+    fn level_for(&self, target: &str) -> LevelFilter {
+        // The longest matching prefix wins, so `ssc::eval=trace` beats `ssc=info`
+        self.directives
+            .iter()
+            .filter(|(prefix, _)| target.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default_level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.directives
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default_level, |max, level| max.max(level))
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let level = match record.level() {
+            Level::Error => "ERROR".red(),
+            Level::Warn => "WARN".yellow(),
+            Level::Info => "INFO".green(),
+            Level::Debug => "DEBUG".blue(),
+            Level::Trace => "TRACE".dimmed(),
+        };
+        eprintln!("{} {} {}", level, record.target().dimmed(), record.args());
+
+        if let Some(file) = &self.file {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+            let line = json!({
+                "timestamp": timestamp,
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            if let Ok(mut file) = file.lock() {
+                let _ = writeln!(file, "{}", line);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            if let Ok(mut file) = file.lock() {
+                let _ = file.flush();
+            }
+        }
+    }
+}
+
+/// Parse a `RUST_LOG`-style filter, e.g. `info,ssc::qdrant_client=debug`.
+/// A bare level replaces the default, `target=level` overrides it for that target.
+fn parse_filter(
+    filter: &str,
+    default_level: &mut LevelFilter,
+) -> Result<Vec<(String, LevelFilter)>, String> {
+    let mut directives = Vec::new();
+
+    for directive in filter.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        match directive.split_once('=') {
+            Some((target, level)) => {
+                let level = level
+                    .parse::<LevelFilter>()
+                    .map_err(|_| format!("invalid log level '{}' for '{}'", level, target))?;
+                directives.push((target.to_string(), level));
+            }
+            None => match directive.parse::<LevelFilter>() {
+                Ok(level) => *default_level = level,
+                // A bare target name enables everything for it, like env_logger
+                Err(_) => directives.push((directive.to_string(), LevelFilter::Trace)),
+            },
+        }
+    }
+
+    Ok(directives)
+}
+
+/// Install the global logger.
+///
+/// `verbosity` is the number of `-v` flags, or -1 for `-q`. `RUST_LOG` takes precedence
+/// over the flags when set.
+pub fn init(verbosity: i8, log_file: Option<&Path>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut default_level = match verbosity {
+        i8::MIN..=-1 => LevelFilter::Error,
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+
+    let directives = match std::env::var("RUST_LOG") {
+        Ok(filter) => parse_filter(&filter, &mut default_level)?,
+        Err(_) => Vec::new(),
+    };
+
+    let file = match log_file {
+        Some(path) => Some(Mutex::new(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        )),
+        None => None,
+    };
+
+    let logger = Logger {
+        default_level,
+        directives,
+        file,
+    };
+    log::set_max_level(logger.max_level());
+    log::set_logger(Box::leak(Box::new(logger))).map_err(|e| e.to_string())?;
+
+    Ok(())
+}
@@ -1,21 +1,33 @@
 mod ai;
+mod chat;
 mod cli_ui;
+mod context;
+mod embedding_cache;
+mod embeddings;
+mod eval;
+mod grounding;
+mod local_store;
+mod logging;
+mod markdown;
+mod models;
+mod prompts;
+mod providers;
 mod qdrant_client;
+mod vector_store;
 
-use clap::Parser;
-use cli_ui::{CliUI, FileInfo};
+use clap::{Parser, Subcommand, ValueEnum};
+use cli_ui::{CliUI, DocumentInfo, FileInfo, OutputFormat};
 use colored::Colorize;
-use fastembed::{
-    EmbeddingModel, InitOptions, RerankInitOptions, RerankerModel, SparseModel,
-    SparseTextEmbedding, TextEmbedding, TextRerank,
-};
+use fastembed::SparseTextEmbedding;
 use markitdown::MarkItDown;
-use regex::Regex;
+use serde::Serialize;
 use std::env;
 use std::fs;
-use std::path::Path;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
 use text_splitter::MarkdownSplitter;
+use vector_store::VectorStore;
 use walkdir::WalkDir;
 
 #[derive(Parser)]
@@ -39,126 +51,424 @@ struct Args {
     /// Search query for semantic search
     #[arg(long)]
     search: Option<String>,
+
+    /// Print the retrieved chunks instead of generating an AI response
+    #[arg(long)]
+    retrieve_only: bool,
+
+    /// Wait for the complete AI response instead of printing it as it is generated
+    #[arg(long)]
+    no_stream: bool,
+
+    /// Prompt template for the AI response: a built-in (default, summarize, compare), a
+    /// template in PROMPTS_DIR, or a file path
+    #[arg(long, default_value = "default")]
+    prompt: String,
+
+    /// Only answer from the documents: skip the LLM when no result is relevant enough, and
+    /// flag sentences of the answer that no source supports
+    #[arg(long)]
+    strict: bool,
+
+    /// Reranker score the best result needs to reach in --strict mode
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    strict_threshold: f32,
+
+    /// Format of the results written to stdout; diagnostics go to stderr for json and ndjson
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /// Show more log output (-v info, -vv debug with per-stage timings, -vvv trace)
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Only print results, warnings and errors
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// Also write logs to this file as JSON lines
+    #[arg(long, global = true)]
+    log_file: Option<PathBuf>,
+
+    #[command(flatten)]
+    search_args: SearchArgs,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-fn format_markdown(markdown: &str) -> String {
-    let mut result = markdown.to_string();
+#[derive(clap::Args)]
+#[command(next_help_heading = "Search Options")]
+struct SearchArgs {
+    /// Only search documents under this path, compared by whole path components
+    #[arg(long)]
+    path: Option<String>,
 
-    // Bold
-    let bold_regex = Regex::new(r"\*\*(.*?)\*\*").unwrap();
-    result = bold_regex
-        .replace_all(&result, |caps: &regex::Captures| {
-            format!("\x1b[1m{}\x1b[0m", &caps[1])
-        })
-        .to_string();
+    /// Only search documents with these file extensions (comma separated)
+    #[arg(long, value_delimiter = ',')]
+    ext: Vec<String>,
 
-    // Italic
-    let italic_regex = Regex::new(r"\*(.*?)\*").unwrap();
-    result = italic_regex
-        .replace_all(&result, |caps: &regex::Captures| {
-            format!("\x1b[3m{}\x1b[0m", &caps[1])
-        })
-        .to_string();
+    /// Only search documents modified at or after this date (YYYY-MM-DD or Unix timestamp)
+    #[arg(long, value_parser = parse_timestamp)]
+    after: Option<u64>,
 
-    // Underline
-    let underline_regex = Regex::new(r"__(.*?)__").unwrap();
-    result = underline_regex
-        .replace_all(&result, |caps: &regex::Captures| {
-            format!("\x1b[4m{}\x1b[0m", &caps[1])
-        })
-        .to_string();
+    /// Only search documents modified before this date (YYYY-MM-DD or Unix timestamp)
+    #[arg(long, value_parser = parse_timestamp)]
+    before: Option<u64>,
 
-    // Strikethrough
-    let strikethrough_regex = Regex::new(r"~~(.*?)~~").unwrap();
-    result = strikethrough_regex
-        .replace_all(&result, |caps: &regex::Captures| {
-            format!("\x1b[9m{}\x1b[0m", &caps[1])
-        })
-        .to_string();
+    /// Retrieval stages to run: a single index, fused without reranking, or the full pipeline
+    #[arg(long, value_enum, default_value_t = vector_store::SearchMode::Full)]
+    mode: vector_store::SearchMode,
 
-    // Blockquote
-    let blockquote_regex = Regex::new(r"(> ?.*)").unwrap();
-    result = blockquote_regex
-        .replace_all(&result, |caps: &regex::Captures| {
-            format!("\x1b[3m\x1b[34m\x1b[1m{}\x1b[22m\x1b[0m", &caps[1])
-        })
-        .to_string();
+    /// Candidates fetched from the sparse (SPLADE) index
+    #[arg(long, default_value_t = 25)]
+    sparse_limit: u64,
 
-    // Lists (bold magenta number and bullet)
-    let list_regex = Regex::new(r"([\d]+\.|-|\*) (.*)").unwrap();
-    result = list_regex
-        .replace_all(&result, |caps: &regex::Captures| {
-            format!("\x1b[35m\x1b[1m{}\x1b[22m\x1b[0m {}", &caps[1], &caps[2])
-        })
-        .to_string();
-
-    // Block code (black on gray)
-    let block_code_regex = Regex::new(r"(?s)```(\w+)?\n(.*?)\n```").unwrap();
-    result = block_code_regex
-        .replace_all(&result, |caps: &regex::Captures| {
-            let lang = if caps.get(1).is_some() { &caps[1] } else { "" };
-            format!(
-                "\x1b[3m\x1b[1m{}\x1b[22m\x1b[0m\n\x1b[57;107m{}\x1b[0m\n",
-                lang, &caps[2]
-            )
-        })
-        .to_string();
+    /// Candidates fetched from the dense index
+    #[arg(long, default_value_t = 25)]
+    dense_limit: u64,
 
-    // Inline code (black on gray)
-    let inline_code_regex = Regex::new(r"`(.*?)`").unwrap();
-    result = inline_code_regex
-        .replace_all(&result, |caps: &regex::Captures| {
-            format!("\x1b[57;107m{}\x1b[0m", &caps[1])
-        })
-        .to_string();
+    /// How sparse and dense candidates are fused
+    #[arg(long, value_enum, default_value_t = vector_store::FusionMethod::Rrf)]
+    fusion: vector_store::FusionMethod,
 
-    // Headers (cyan bold)
-    let header_regex = Regex::new(r"(#{1,6}) (.*?)\n").unwrap();
-    result = header_regex
-        .replace_all(&result, |caps: &regex::Captures| {
-            format!("\x1b[36m\x1b[1m{} {}\x1b[22m\x1b[0m\n", &caps[1], &caps[2])
-        })
-        .to_string();
+    /// Fused candidates passed to the reranker
+    #[arg(long, default_value_t = 50)]
+    rerank_limit: u64,
 
-    // Headers with a single line of text followed by 2 or more equal signs
-    let header_equals_regex = Regex::new(r"(.*?\n={2,}\n)").unwrap();
-    result = header_equals_regex
-        .replace_all(&result, |caps: &regex::Captures| {
-            format!("\x1b[36m\x1b[1m{}\x1b[22m\x1b[0m\n", &caps[1])
-        })
-        .to_string();
+    /// Results kept after reranking and sent to the LLM
+    #[arg(long, default_value_t = 10)]
+    top_k: usize,
 
-    // Headers with a single line of text followed by 2 or more dashes
-    let header_dashes_regex = Regex::new(r"(.*?\n-{2,}\n)").unwrap();
-    result = header_dashes_regex
-        .replace_all(&result, |caps: &regex::Captures| {
-            format!("\x1b[36m\x1b[1m{}\x1b[22m\x1b[0m\n", &caps[1])
-        })
-        .to_string();
-
-    // Images (blue underlined)
-    let image_regex = Regex::new(r"!\[(.*?)\]\((.*?)\)").unwrap();
-    result = image_regex
-        .replace_all(&result, |caps: &regex::Captures| {
-            format!(
-                "\x1b[34m{}\x1b[0m (\x1b[34m\x1b[4m{}\x1b[0m)",
-                &caps[1], &caps[2]
-            )
-        })
-        .to_string();
-
-    // Links (blue underlined)
-    let link_regex = Regex::new(r"!?\[(.*?)\]\((.*?)\)").unwrap();
-    result = link_regex
-        .replace_all(&result, |caps: &regex::Captures| {
-            format!(
-                "\x1b[34m{}\x1b[0m (\x1b[34m\x1b[4m{}\x1b[0m)",
-                &caps[1], &caps[2]
-            )
+    /// Drop results with a reranker score below this (full mode only)
+    #[arg(long, allow_negative_numbers = true)]
+    min_score: Option<f32>,
+
+    /// Have the LLM expand the query before searching; results for every variant are fused
+    #[arg(long, value_enum)]
+    expand: Option<ai::QueryExpansion>,
+
+    /// Number of reformulations generated by --expand multi
+    #[arg(long, default_value_t = 3)]
+    expansions: usize,
+}
+
+impl SearchArgs {
+    fn to_options(&self) -> vector_store::SearchOptions {
+        vector_store::SearchOptions {
+            filter: vector_store::SearchFilter {
+                path_prefix: self.path.clone(),
+                extensions: self.ext.clone(),
+                modified_after: self.after,
+                modified_before: self.before,
+            },
+            mode: self.mode,
+            sparse_limit: self.sparse_limit,
+            dense_limit: self.dense_limit,
+            fusion: self.fusion,
+            rerank_limit: self.rerank_limit,
+            top_k: self.top_k,
+            min_score: self.min_score,
+        }
+    }
+
+    fn expansion(&self) -> Option<ai::Expansion> {
+        self.expand.map(|method| ai::Expansion {
+            method,
+            count: self.expansions,
         })
-        .to_string();
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List documents stored in the index
+    Ls {
+        /// Only show documents under this path, compared by whole path components
+        #[arg(long)]
+        path: Option<String>,
+
+        /// Only show documents with this file extension (e.g. pdf)
+        #[arg(long)]
+        ext: Option<String>,
+
+        /// Field to sort documents by
+        #[arg(long, value_enum, default_value_t = SortField::Path)]
+        sort: SortField,
+
+        /// Reverse the sort order
+        #[arg(short, long)]
+        reverse: bool,
+    },
+
+    /// Print the stored markdown for an indexed document
+    Cat {
+        /// Path of the document, as it was indexed
+        path: String,
+
+        /// Print the markdown without terminal formatting
+        #[arg(long)]
+        raw: bool,
+    },
+
+    /// Measure retrieval quality against a JSONL file of labelled queries
+    Eval {
+        /// JSONL file with one {"query", "relevant": [{"file_path", "chunk_index"?}]} per line
+        file: PathBuf,
+
+        /// Search modes to evaluate (comma separated)
+        #[arg(long, value_enum, value_delimiter = ',', default_values_t = [
+            vector_store::SearchMode::Dense,
+            vector_store::SearchMode::Sparse,
+            vector_store::SearchMode::Hybrid,
+            vector_store::SearchMode::Full,
+        ])]
+        modes: Vec<vector_store::SearchMode>,
+
+        /// Compare against a previously saved report and fail on regressions
+        #[arg(long)]
+        baseline: Option<PathBuf>,
+
+        /// Save this run's report, e.g. as a new baseline
+        #[arg(long)]
+        save: Option<PathBuf>,
+
+        /// Allowed drop in any metric before it counts as a regression
+        #[arg(long, default_value_t = 0.01)]
+        tolerance: f64,
+
+        #[command(flatten)]
+        search_args: SearchArgs,
+    },
+
+    /// Ask follow-up questions about your documents in an interactive session
+    Chat {
+        /// Wait for each complete AI response instead of printing it as it is generated
+        #[arg(long)]
+        no_stream: bool,
+
+        /// Prompt template for the AI responses (see --prompt on search)
+        #[arg(long, default_value = "default")]
+        prompt: String,
+
+        /// Only answer from the documents (see --strict on search)
+        #[arg(long)]
+        strict: bool,
+
+        /// Reranker score the best result needs to reach in --strict mode
+        #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+        strict_threshold: f32,
+
+        #[command(flatten)]
+        search_args: SearchArgs,
+    },
+
+    /// Manage the embedding and reranking models
+    Models {
+        #[command(subcommand)]
+        command: ModelsCommand,
+    },
+
+    /// Set up the Qdrant collections
+    Index {
+        #[command(subcommand)]
+        command: IndexCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum ModelsCommand {
+    /// Download every model into the model cache, e.g. before going offline
+    Download,
+
+    /// Show the model cache directory and which models are in it
+    List,
+}
+
+#[derive(Subcommand)]
+enum IndexCommand {
+    /// Create the collections with storage and index settings for large corpora; they can't
+    /// be changed afterwards without reindexing
+    Create {
+        /// Compress dense vectors in memory
+        #[arg(long, value_enum, default_value_t = qdrant_client::Quantization::None)]
+        quantization: qdrant_client::Quantization,
+
+        /// Keep vectors, the sparse index and payloads on disk instead of in memory
+        #[arg(long)]
+        on_disk: bool,
+
+        /// Edges per node in the HNSW graph; higher is more accurate but uses more memory
+        #[arg(long)]
+        hnsw_m: Option<u64>,
+
+        /// Neighbours considered while building the HNSW graph; higher is more accurate but
+        /// slower to index
+        #[arg(long)]
+        hnsw_ef_construct: Option<u64>,
+
+        /// Weight sparse terms by inverse document frequency
+        #[arg(long)]
+        sparse_idf: bool,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum SortField {
+    Path,
+    Size,
+    Modified,
+    Chunks,
+}
+
+/// Parse a `YYYY-MM-DD` date (UTC midnight) or a raw Unix timestamp
+fn parse_timestamp(value: &str) -> Result<u64, String> {
+    if let Ok(timestamp) = value.parse::<u64>() {
+        return Ok(timestamp);
+    }
+
+    let parts = value
+        .split('-')
+        .map(|part| part.parse::<i64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| format!("expected YYYY-MM-DD or a Unix timestamp, got '{}'", value))?;
+    let [year, month, day] = parts[..] else {
+        return Err(format!(
+            "expected YYYY-MM-DD or a Unix timestamp, got '{}'",
+            value
+        ));
+    };
+    let leap_year = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 if leap_year => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if !(1..=12).contains(&month) || !(1..=days_in_month).contains(&day) || year < 1970 {
+        return Err(format!("'{}' is not a valid date", value));
+    }
 
-    result
+    // Days-from-civil conversion (Howard Hinnant's algorithm)
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+
+    Ok(days as u64 * 86_400)
+}
+
+/// Records written to stdout for `--output ndjson`
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum OutputEvent<'a> {
+    Hit(&'a vector_store::SearchResult),
+    Answer { content: &'a str },
+    Unsupported { sentences: &'a [String] },
+    File(&'a FileEvent),
+}
+
+/// Search results written to stdout for `--output json`
+#[derive(Serialize)]
+struct SearchOutput<'a> {
+    query: &'a str,
+    results: &'a [vector_store::SearchResult],
+    answer: Option<&'a str>,
+    /// Sentences of the answer not supported by the sources, with --strict
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unsupported: Option<&'a [String]>,
+}
+
+/// Outcome of indexing a single file
+#[derive(Serialize)]
+struct FileEvent {
+    file_path: String,
+    status: FileStatus,
+    chunks: usize,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum FileStatus {
+    Indexed,
+    Failed,
+}
+
+impl FileEvent {
+    fn indexed(file_path: &Path, chunks: usize) -> Self {
+        Self {
+            file_path: file_path.display().to_string(),
+            status: FileStatus::Indexed,
+            chunks,
+            error: None,
+        }
+    }
+
+    fn failed(file_path: &Path, error: impl ToString) -> Self {
+        Self {
+            file_path: file_path.display().to_string(),
+            status: FileStatus::Failed,
+            chunks: 0,
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+/// Load the models indexing needs; search loads its models lazily instead
+fn load_index_models(
+    ui: &CliUI,
+) -> Result<(embeddings::Embedder, SparseTextEmbedding), Box<dyn std::error::Error>> {
+    let cache = models::ModelCache::from_env();
+    let init_spinner = ui.show_loading("Loading embedding models...");
+
+    let models =
+        embeddings::Embedder::from_env(&cache).and_then(|dense| Ok((dense, cache.load_sparse()?)));
+
+    init_spinner.finish_and_clear();
+    let models = models?;
+    ui.print_success("AI models loaded successfully");
+
+    Ok(models)
+}
+
+fn manage_models(command: &ModelsCommand, ui: &CliUI) -> Result<(), Box<dyn std::error::Error>> {
+    let cache = models::ModelCache::from_env();
+
+    match command {
+        ModelsCommand::Download => {
+            let cache = cache.online();
+            ui.print_info(&format!("Downloading models to {}", cache.dir().display()));
+            cache.load_dense()?;
+            ui.print_success("Dense embedding model ready");
+            cache.load_sparse()?;
+            ui.print_success("Sparse embedding model ready");
+            cache.load_reranker()?;
+            ui.print_success("Reranker model ready");
+        }
+        ModelsCommand::List => {
+            ui.print_section("Models");
+            ui.print_info(&format!("Model cache: {}", cache.dir().display()));
+            for model in cache.models()? {
+                let missing = cache.missing_files(&model);
+                if missing.is_empty() {
+                    ui.print_success(&format!("{} ({}): downloaded", model.repo, model.role));
+                } else {
+                    ui.print_warning(&format!(
+                        "{} ({}): missing {}",
+                        model.repo,
+                        model.role,
+                        missing.join(", ")
+                    ));
+                }
+            }
+        }
+    }
+
+    Ok(())
 }
 
 fn convert_file_to_markdown(file_path: &Path) -> Result<String, String> {
@@ -206,132 +516,560 @@ fn chunk_markdown_content(content: &str, chunk_size: usize) -> Vec<String> {
         .collect()
 }
 
-fn generate_dense_embeddings(
-    chunks: &[String],
-    model: &mut TextEmbedding,
-) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error>> {
-    // Prepare documents with "passage:" prefix for better retrieval performance
-    let documents: Vec<String> = chunks
+fn matches_extension(file_path: &str, ext: &str) -> bool {
+    Path::new(file_path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case(ext.trim_start_matches('.')))
+}
+
+async fn list_documents(
+    vector_store: &impl VectorStore,
+    path_prefix: Option<&str>,
+    ext: Option<&str>,
+    sort: SortField,
+    reverse: bool,
+    ui: &CliUI,
+) -> Result<(), Box<dyn std::error::Error>> {
+    ui.print_section("Indexed Documents");
+
+    let mut files = vector_store.list_files().await?;
+    files.retain(|file| {
+        path_prefix.is_none_or(|prefix| vector_store::in_path(&file.file_path, prefix))
+            && ext.is_none_or(|ext| matches_extension(&file.file_path, ext))
+    });
+
+    match sort {
+        SortField::Path => files.sort_by(|a, b| a.file_path.cmp(&b.file_path)),
+        SortField::Size => files.sort_by_key(|file| file.file_size),
+        SortField::Modified => files.sort_by_key(|file| file.modified_time),
+        SortField::Chunks => files.sort_by_key(|file| file.chunk_count),
+    }
+    if reverse {
+        files.reverse();
+    }
+
+    if !ui.is_text() {
+        for file in &files {
+            ui.emit_ndjson(file);
+        }
+        ui.emit_json(&files);
+        return Ok(());
+    }
+
+    let documents = files
         .iter()
-        .map(|chunk| format!("passage: {}", chunk))
-        .collect();
+        .map(|file| DocumentInfo {
+            path: file.file_path.clone(),
+            size: format!("{} KB", file.file_size / 1024),
+            modified: cli_ui::format_timestamp(file.modified_time),
+            chunks: file.chunk_count,
+            hash: file.content_hash.chars().take(12).collect(),
+        })
+        .collect::<Vec<_>>();
 
-    let embeddings = model.embed(documents, None)?;
+    ui.print_documents_table(&documents);
+    ui.print_info(&format!("{} documents", documents.len()));
 
-    Ok(embeddings)
+    Ok(())
 }
 
-fn generate_sparse_embeddings(
-    chunks: &[String],
-    model: &mut SparseTextEmbedding,
-) -> Result<Vec<fastembed::SparseEmbedding>, Box<dyn std::error::Error>> {
-    // Prepare documents with "passage:" prefix for better retrieval performance
-    let documents: Vec<String> = chunks
-        .iter()
-        .map(|chunk| format!("passage: {}", chunk))
-        .collect();
+async fn print_document(
+    vector_store: &impl VectorStore,
+    file_path: &str,
+    raw: bool,
+    ui: &CliUI,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let file = vector_store
+        .get_file(file_path)
+        .await?
+        .ok_or_else(|| format!("'{}' is not indexed (see `ssc ls`)", file_path))?;
+
+    if !ui.is_text() {
+        ui.emit_ndjson(&file);
+        ui.emit_json(&file);
+        return Ok(());
+    }
+
+    let markdown = file.markdown_content.unwrap_or_default();
+    if markdown.is_empty() {
+        ui.print_warning(&format!("No markdown stored for '{}'", file.file_path));
+        return Ok(());
+    }
+
+    ui.print_section(&file.file_name);
+    if raw {
+        println!("{}", markdown);
+    } else {
+        println!("{}", markdown::format_markdown(&markdown));
+    }
+
+    Ok(())
+}
+
+/// Say how much of the retrieved context is sent to the LLM
+fn report_context(context: &context::Context, retrieved: usize, ui: &CliUI) {
+    let mut message = format!(
+        "Sending {} sources to the LLM ({} of {} chunks, ~{} tokens)",
+        context.sources.len(),
+        context.chunks_used,
+        retrieved,
+        context.tokens
+    );
+    if context.duplicates > 0 {
+        message.push_str(&format!(", {} duplicates removed", context.duplicates));
+    }
+    ui.print_info(&message);
 
-    let embeddings = model.embed(documents, None)?;
+    if context.over_budget > 0 {
+        ui.print_warning(&format!(
+            "{} chunks did not fit in the context budget; raise OPENAI_CONTEXT_BUDGET to include them",
+            context.over_budget
+        ));
+    }
+}
+
+/// The --strict relevance threshold. Only reranker scores are on a fixed scale, so other
+/// modes keep the answer check but always call the LLM.
+fn min_relevance(
+    strict: bool,
+    threshold: f32,
+    mode: vector_store::SearchMode,
+    ui: &CliUI,
+) -> Option<f32> {
+    if !strict {
+        return None;
+    }
+    if mode != vector_store::SearchMode::Full {
+        ui.print_warning("--strict only checks relevance with reranker scores (--mode full)");
+        return None;
+    }
+    Some(threshold)
+}
+
+/// Warn about sentences of an answer that the sources don't support
+fn report_unsupported(unsupported: &[String], ui: &CliUI) {
+    if unsupported.is_empty() {
+        ui.print_success("Every statement in the answer is supported by the sources");
+        return;
+    }
+
+    ui.print_warning(&format!(
+        "{} statements in the answer are not supported by the sources:",
+        unsupported.len()
+    ));
+    for sentence in unsupported {
+        ui.print_warning(&format!("  \"{}\"", sentence));
+    }
+}
 
-    Ok(embeddings)
+/// How `perform_search` answers from the retrieved chunks
+struct AnswerConfig {
+    prompt: prompts::PromptTemplate,
+    stream: bool,
+    /// Check the answer against the sources (--strict)
+    strict: bool,
+    /// Skip the LLM unless a result's reranker score reaches this
+    min_relevance: Option<f32>,
 }
 
 async fn perform_search(
-    vector_store: &qdrant_client::QdrantVectorStore,
+    vector_store: &impl VectorStore,
     query: &str,
-    dense_model: &mut TextEmbedding,
-    sparse_model: &mut SparseTextEmbedding,
-    reranker: &mut TextRerank,
+    models: &mut models::SearchModels,
+    search_args: &SearchArgs,
+    // How to answer, or `None` to only print the retrieved chunks
+    answer: Option<&AnswerConfig>,
     ui: &CliUI,
 ) -> Result<(), Box<dyn std::error::Error>> {
     ui.print_section("Searching");
 
+    let options = search_args.to_options();
+    let extra_queries = match search_args.expansion() {
+        Some(expansion) => {
+            let expand_spinner = ui.show_loading("Expanding query...");
+            let queries = ai::LlmClient::from_env()?
+                .expand_query(query, &expansion)
+                .await;
+            expand_spinner.finish_and_clear();
+            queries?
+        }
+        None => Vec::new(),
+    };
+
     let search_spinner = ui.show_loading("Searching vector database...");
     let results = vector_store
-        .hybrid_search(query, dense_model, sparse_model, reranker)
+        .hybrid_search(query, &extra_queries, models, &options)
         .await?;
     search_spinner.finish_and_clear();
 
     if results.is_empty() {
         ui.print_warning("No results found.");
+        ui.emit_json(&SearchOutput {
+            query,
+            results: &results,
+            answer: None,
+            unsupported: None,
+        });
         return Ok(());
     }
 
     ui.print_success(&format!("Found {} results", results.len()));
+    for result in &results {
+        ui.emit_ndjson(&OutputEvent::Hit(result));
+    }
 
-    let llm_spinner = ui.show_loading("Generating response from LLM...");
+    let Some(answer) = answer else {
+        let display_results = results
+            .iter()
+            .map(|result| cli_ui::SearchResult {
+                file_name: format!("{} (chunk {})", result.file_name, result.chunk_index),
+                file_path: result.file_path.clone(),
+                content: result.chunk_content.clone(),
+                score: result.similarity_score as f32,
+            })
+            .collect::<Vec<_>>();
+        if ui.is_text() {
+            ui.print_search_results(query, &display_results);
+        }
+        ui.emit_json(&SearchOutput {
+            query,
+            results: &results,
+            answer: None,
+            unsupported: None,
+        });
+        return Ok(());
+    };
+
+    if let Some(threshold) = answer.min_relevance {
+        if !grounding::is_relevant(&results, threshold) {
+            ui.print_warning(&format!(
+                "Nothing relevant found in the documents: no result reached a reranker score of {}",
+                threshold
+            ));
+            ui.print_info("Use --retrieve-only to see what was found, or lower --strict-threshold");
+            ui.emit_json(&SearchOutput {
+                query,
+                results: &results,
+                answer: None,
+                unsupported: None,
+            });
+            return Ok(());
+        }
+    }
 
     // call LLM with results
-    let openai_client = ai::OpenAiClient::new(
-        env::var("OPENAI_API_KEY").unwrap(),
-        env::var("OPENAI_URL").unwrap(),
-        env::var("OPENAI_MODEL").unwrap(),
-    );
-    let response = openai_client
-        .generate_response(query, &results, None)
-        .await?;
+    let llm_client = ai::LlmClient::from_env()?;
+    let context = context::select_sources(&results, llm_client.config().context_budget);
+    report_context(&context, results.len(), ui);
+
+    let llm_spinner = ui.show_loading("Generating response from LLM...");
 
-    llm_spinner.finish_and_clear();
+    // Only text output is rendered as it arrives; JSON consumers get the complete answer
+    let prompt = &answer.prompt;
+    let response = if answer.stream && ui.is_text() {
+        let mut markdown_stream = markdown::MarkdownStream::new();
+        let mut started = false;
+
+        let response = llm_client
+            .generate_response_stream(prompt, query, &context.sources, |token| {
+                if !started {
+                    llm_spinner.finish_and_clear();
+                    ui.print_section("AI Response");
+                    started = true;
+                }
+                print!("{}", markdown_stream.push(token).bright_white());
+                io::stdout().flush().ok();
+            })
+            .await?;
+
+        llm_spinner.finish_and_clear();
+        println!("{}", markdown_stream.finish().bright_white());
+        response
+    } else {
+        let response = llm_client
+            .generate_response(prompt, query, &context.sources)
+            .await?;
 
-    ui.print_section("AI Response");
-    println!("{}", format_markdown(&response).bright_white());
+        llm_spinner.finish_and_clear();
+
+        if ui.is_text() {
+            ui.print_section("AI Response");
+            println!("{}", markdown::format_markdown(&response).bright_white());
+        }
+        response
+    };
+    ui.emit_ndjson(&OutputEvent::Answer { content: &response });
+
+    let unsupported = answer
+        .strict
+        .then(|| grounding::unsupported_sentences(&response, &context.sources));
+    if let Some(unsupported) = &unsupported {
+        report_unsupported(unsupported, ui);
+        ui.emit_ndjson(&OutputEvent::Unsupported {
+            sentences: unsupported,
+        });
+    }
+    ui.emit_json(&SearchOutput {
+        query,
+        results: &results,
+        answer: Some(&response),
+        unsupported: unsupported.as_deref(),
+    });
 
     Ok(())
 }
 
 #[tokio::main]
 async fn main() {
-    let ui = CliUI::new();
-    ui.print_header();
+    let args = Args::parse();
 
-    // Load environment variables from .env file if it exists
+    // Load environment variables from .env file if it exists, before logging reads RUST_LOG
     dotenv::dotenv().ok();
 
-    let args = Args::parse();
+    let ui = CliUI::with_options(args.output, args.quiet);
 
-    ui.print_section("Initializing AI Models");
-    let init_spinner = ui.show_loading("Loading embedding models...");
+    let verbosity = if args.quiet { -1 } else { args.verbose as i8 };
+    if let Err(e) = logging::init(verbosity, args.log_file.as_deref()) {
+        ui.print_error(&format!("Failed to initialize logging: {}", e));
+        std::process::exit(1);
+    }
 
-    let qdrant_url = env::var("QDRANT_URL").unwrap_or_else(|_| {
-        ui.print_error("Error! Expected QDRANT_URL to be set in .env");
+    ui.print_header();
+
+    // Model management works without Qdrant
+    if let Some(Command::Models { command }) = &args.command {
+        if let Err(e) = manage_models(command, &ui) {
+            ui.print_error(&e.to_string());
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    ui.print_section("Initializing");
+    let backend = env::var("VECTOR_STORE").unwrap_or_default();
+
+    if let Some(Command::Index { command }) = &args.command {
+        if backend.eq_ignore_ascii_case("local") {
+            ui.print_info("The local index needs no setup; it is created when first used");
+            return;
+        }
+        if let Err(e) = create_index(&connect_qdrant(&ui).await, command, &ui).await {
+            ui.print_error(&e.to_string());
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    match backend.to_lowercase().as_str() {
+        "" | "qdrant" => {
+            let vector_store = connect_qdrant(&ui).await;
+            if let Err(e) = vector_store.ensure_collections().await {
+                ui.print_error(&format!("Failed to set up the Qdrant collections: {}", e));
+                std::process::exit(1);
+            }
+            run(&vector_store, args, &ui).await
+        }
+        "local" => run(&open_local_store(&ui), args, &ui).await,
+        _ => {
+            ui.print_error(&format!(
+                "Unknown VECTOR_STORE '{}', expected qdrant or local",
+                backend
+            ));
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn connect_qdrant(ui: &CliUI) -> qdrant_client::QdrantVectorStore {
+    let settings = qdrant_client::QdrantSettings::from_env().unwrap_or_else(|e| {
+        ui.print_error(&format!("Error! {}", e));
         std::process::exit(1);
     });
 
-    // Initialize Qdrant client
-    let vector_store = match qdrant_client::QdrantVectorStore::new(&qdrant_url).await {
+    let connect_spinner = ui.show_loading("Connecting to Qdrant...");
+    let store = qdrant_client::QdrantVectorStore::connect(&settings).await;
+    connect_spinner.finish_and_clear();
+
+    match store {
         Ok(store) => {
-            init_spinner.finish_and_clear();
-            ui.print_success("Connected to Qdrant vector database");
+            ui.print_success(&format!(
+                "Connected to Qdrant {} at {}",
+                store.server_version(),
+                settings.url
+            ));
             store
         }
         Err(e) => {
-            init_spinner.finish_and_clear();
             ui.print_error(&format!("Failed to connect to Qdrant: {}", e));
-            ui.print_error(&format!("Make sure Qdrant is running on {}", qdrant_url));
             std::process::exit(1);
         }
+    }
+}
+
+async fn create_index(
+    vector_store: &qdrant_client::QdrantVectorStore,
+    command: &IndexCommand,
+    ui: &CliUI,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let IndexCommand::Create {
+        quantization,
+        on_disk,
+        hnsw_m,
+        hnsw_ef_construct,
+        sparse_idf,
+    } = command;
+    let settings = qdrant_client::CollectionSettings {
+        quantization: *quantization,
+        on_disk: *on_disk,
+        hnsw_m: *hnsw_m,
+        hnsw_ef_construct: *hnsw_ef_construct,
+        sparse_idf: *sparse_idf,
     };
 
-    let mut dense_model =
-        TextEmbedding::try_new(InitOptions::new(EmbeddingModel::BGESmallENV15)).unwrap();
-    let mut sparse_model =
-        SparseTextEmbedding::try_new(fastembed::SparseInitOptions::new(SparseModel::SPLADEPPV1))
-            .unwrap();
-    let mut reranker =
-        TextRerank::try_new(RerankInitOptions::new(RerankerModel::JINARerankerV1TurboEn)).unwrap();
+    vector_store.create_collections(&settings).await?;
+    ui.print_success(&format!("Created the Qdrant collections: {:?}", settings));
 
-    init_spinner.finish_and_clear();
-    ui.print_success("AI models loaded successfully");
+    Ok(())
+}
+
+fn open_local_store(ui: &CliUI) -> local_store::LocalVectorStore {
+    let dir = local_store::store_dir_from_env();
+    let open_spinner = ui.show_loading("Opening local index...");
+    let store = local_store::LocalVectorStore::open(&dir);
+    open_spinner.finish_and_clear();
+
+    match store {
+        Ok(store) => {
+            ui.print_success(&format!("Opened local index in {}", dir.display()));
+            store
+        }
+        Err(e) => {
+            ui.print_error(&format!(
+                "Failed to open local index in {}: {}",
+                dir.display(),
+                e
+            ));
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Run the command, search or indexing the arguments ask for against `vector_store`
+async fn run(vector_store: &impl VectorStore, args: Args, ui: &CliUI) {
+    if let Some(command) = args.command {
+        let result = match command {
+            Command::Ls {
+                path,
+                ext,
+                sort,
+                reverse,
+            } => {
+                list_documents(
+                    vector_store,
+                    path.as_deref(),
+                    ext.as_deref(),
+                    sort,
+                    reverse,
+                    ui,
+                )
+                .await
+            }
+            Command::Cat { path, raw } => print_document(vector_store, &path, raw, ui).await,
+            Command::Eval {
+                file,
+                modes,
+                baseline,
+                save,
+                tolerance,
+                search_args,
+            } => {
+                let mut models = models::SearchModels::new(&models::ModelCache::from_env());
+                let config = eval::EvalConfig {
+                    eval_file: file,
+                    modes,
+                    options: search_args.to_options(),
+                    expansion: search_args.expansion(),
+                    baseline_file: baseline,
+                    save_file: save,
+                    tolerance,
+                };
+                eval::run(vector_store, &mut models, &config, ui).await
+            }
+            Command::Chat {
+                no_stream,
+                prompt,
+                strict,
+                strict_threshold,
+                search_args,
+            } => match prompts::PromptTemplate::load(&prompt) {
+                Ok(prompt) => {
+                    let mut models = models::SearchModels::new(&models::ModelCache::from_env());
+                    let config = chat::ChatConfig {
+                        options: search_args.to_options(),
+                        expansion: search_args.expansion(),
+                        prompt: if strict { prompt.grounded() } else { prompt },
+                        stream: !no_stream,
+                        strict,
+                        min_relevance: min_relevance(
+                            strict,
+                            strict_threshold,
+                            search_args.mode,
+                            ui,
+                        ),
+                    };
+                    chat::run(vector_store, &mut models, &config, ui).await
+                }
+                Err(e) => Err(e),
+            },
+            Command::Models { .. } | Command::Index { .. } => {
+                unreachable!("handled before opening the vector store")
+            }
+        };
+
+        if let Err(e) = result {
+            ui.print_error(&e.to_string());
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Check the prompt template before spending time loading models
+    let answer = match (&args.search, args.retrieve_only) {
+        (Some(_), false) => match prompts::PromptTemplate::load(&args.prompt) {
+            Ok(prompt) => Some(AnswerConfig {
+                prompt: if args.strict {
+                    prompt.grounded()
+                } else {
+                    prompt
+                },
+                stream: !args.no_stream,
+                strict: args.strict,
+                min_relevance: min_relevance(
+                    args.strict,
+                    args.strict_threshold,
+                    args.search_args.mode,
+                    ui,
+                ),
+            }),
+            Err(e) => {
+                ui.print_error(&e.to_string());
+                std::process::exit(1);
+            }
+        },
+        _ => None,
+    };
 
     // Handle search functionality
     if let Some(query) = args.search {
+        let mut models = models::SearchModels::new(&models::ModelCache::from_env());
         if let Err(e) = perform_search(
-            &vector_store,
+            vector_store,
             &query,
-            &mut dense_model,
-            &mut sparse_model,
-            &mut reranker,
-            &ui,
+            &mut models,
+            &args.search_args,
+            answer.as_ref(),
+            ui,
         )
         .await
         {
@@ -437,13 +1175,30 @@ async fn main() {
 
     if files_to_process.is_empty() {
         ui.print_warning("No files found to process");
+        ui.emit_json(&Vec::<FileEvent>::new());
         return;
     }
 
+    let (mut embedder, mut sparse_model) = load_index_models(ui).unwrap_or_else(|e| {
+        ui.print_error(&e.to_string());
+        std::process::exit(1);
+    });
+    let embedding_cache =
+        embedding_cache::EmbeddingCache::from_env(&models::ModelCache::from_env());
+
     ui.print_section("File Processing");
-    let file_progress = ui.create_file_progress_bar(files_to_process.len());
+    let file_count = files_to_process.len();
+    let file_progress = ui.create_file_progress_bar(file_count);
+    let mut file_events = Vec::new();
+    let mut record_event = |event: FileEvent| {
+        ui.emit_ndjson(&OutputEvent::File(&event));
+        file_events.push(event);
+    };
+
+    // Set when an error would repeat for every remaining file
+    let mut stopped = false;
 
-    for entry in files_to_process {
+    'files: for entry in files_to_process {
         let file_name = entry
             .path()
             .file_name()
@@ -462,13 +1217,13 @@ async fn main() {
                 entry.path().display(),
                 e
             ));
+            record_event(FileEvent::failed(entry.path(), e));
             file_progress.inc(1);
             continue;
         }
 
         let markdown_content = markdown_content.unwrap();
 
-        // Store file metadata in Qdrant
         let metadata = fs::metadata(entry.path()).unwrap();
         let modified_time = metadata
             .modified()
@@ -479,76 +1234,143 @@ async fn main() {
 
         let content_hash = format!("{:x}", md5::compute(&markdown_content));
 
-        let file_id = match vector_store
-            .store_file_metadata(
-                entry.path().to_str().unwrap(),
-                entry
-                    .path()
-                    .file_name()
-                    .unwrap_or_default()
-                    .to_str()
-                    .unwrap(),
-                metadata.len(),
-                modified_time,
-                &content_hash,
-                Some(&markdown_content),
-            )
-            .await
-        {
-            Ok(id) => {
-                ui.print_success(&format!("Stored file in Qdrant with ID: {}", id));
-                id
-            }
-            Err(e) => {
-                ui.print_error(&format!("Failed to store file in Qdrant: {}", e));
-                file_progress.inc(1);
-                continue;
-            }
-        };
-
+        let file_id = vector_store::file_id_for_path(entry.path().to_str().unwrap());
         let chunks = chunk_markdown_content(&markdown_content, 1000);
 
         let embedding_progress = ui.create_embedding_progress_bar(chunks.len());
         embedding_progress.set_message("Generating dense embeddings...");
-        let dense_embeddings = generate_dense_embeddings(&chunks, &mut dense_model).unwrap();
+        let (dense_embeddings, dense_reused) =
+            match embedding_cache.embed_dense(&mut embedder, &chunks).await {
+                Ok(embeddings) => embeddings,
+                Err(e) => {
+                    embedding_progress.finish_and_clear();
+                    ui.print_error(&format!(
+                        "Failed to embed {}: {}",
+                        entry.path().display(),
+                        e
+                    ));
+                    stopped = e.is::<embeddings::DimensionMismatch>();
+                    record_event(FileEvent::failed(entry.path(), e));
+                    file_progress.inc(1);
+                    if stopped {
+                        break;
+                    }
+                    continue;
+                }
+            };
         embedding_progress.set_message("Generating sparse embeddings...");
-        let sparse_embeddings = generate_sparse_embeddings(&chunks, &mut sparse_model).unwrap();
+        let (sparse_embeddings, sparse_reused) =
+            match embedding_cache.embed_sparse(&mut sparse_model, &chunks) {
+                Ok(embeddings) => embeddings,
+                Err(e) => {
+                    embedding_progress.finish_and_clear();
+                    ui.print_error(&format!(
+                        "Failed to embed {}: {}",
+                        entry.path().display(),
+                        e
+                    ));
+                    record_event(FileEvent::failed(entry.path(), e));
+                    file_progress.inc(1);
+                    continue;
+                }
+            };
         embedding_progress.finish_and_clear();
 
         ui.print_success(&format!(
-            "Generated {} dense embeddings",
-            dense_embeddings.len()
+            "Generated {} dense embeddings ({} from cache)",
+            dense_embeddings.len(),
+            dense_reused
         ));
         ui.print_success(&format!(
-            "Generated {} sparse embeddings",
-            sparse_embeddings.len()
+            "Generated {} sparse embeddings ({} from cache)",
+            sparse_embeddings.len(),
+            sparse_reused
         ));
 
         if dense_embeddings.len() != sparse_embeddings.len() {
             ui.print_error("Dense and sparse embeddings have different lengths");
+            record_event(FileEvent::failed(
+                entry.path(),
+                "Dense and sparse embeddings have different lengths",
+            ));
             file_progress.inc(1);
             continue;
         }
 
         let store_progress = ui.create_spinner("Storing embeddings in vector database...");
+        let file_extension = entry
+            .path()
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
         for (i, dense_embedding) in dense_embeddings.iter().enumerate() {
             let sparse_embedding = &sparse_embeddings[i];
-            vector_store
-                .store_embeddings(
-                    file_id.as_str(),
-                    &chunks[i],
-                    i as i32,
-                    dense_embedding,
-                    sparse_embedding,
-                )
+            let chunk = vector_store::ChunkMetadata {
+                file_id: file_id.clone(),
+                file_path: entry.path().to_str().unwrap().to_string(),
+                file_extension: file_extension.clone(),
+                modified_time,
+                chunk_index: i as i32,
+                chunk_content: chunks[i].clone(),
+            };
+            if let Err(e) = vector_store
+                .store_embeddings(&chunk, dense_embedding, sparse_embedding)
                 .await
-                .unwrap();
+            {
+                store_progress.finish_and_clear();
+                ui.print_error(&e.to_string());
+                record_event(FileEvent::failed(entry.path(), e));
+                file_progress.inc(1);
+                continue 'files;
+            }
         }
         store_progress.finish_and_clear();
 
+        // Only now, so a document whose chunks failed keeps its previous version
+        let file = vector_store::FileMetadata {
+            file_path: entry.path().to_str().unwrap().to_string(),
+            file_name,
+            file_size: metadata.len(),
+            modified_time,
+            content_hash,
+            markdown_content: Some(markdown_content),
+        };
+        match vector_store.store_file_metadata(&file, chunks.len()).await {
+            Ok(id) => ui.print_success(&format!("Stored file with ID: {}", id)),
+            Err(e) => {
+                ui.print_error(&format!("Failed to store file: {}", e));
+                record_event(FileEvent::failed(entry.path(), e));
+                file_progress.inc(1);
+                continue;
+            }
+        };
+
+        record_event(FileEvent::indexed(entry.path(), chunks.len()));
         file_progress.inc(1);
     }
 
     file_progress.finish_and_clear();
-    ui.print_completion("All files processed successfully!");
+    ui.emit_json(&file_events);
+
+    let failed = file_events
+        .iter()
+        .filter(|event| matches!(event.status, FileStatus::Failed))
+        .count();
+    let indexed = file_events.len() - failed;
+    if failed == 0 {
+        ui.print_completion(&format!("Indexed all {} files", indexed));
+        return;
+    }
+
+    // Exit non-zero so scripts notice files that are missing from the index
+    let mut summary = format!("Indexed {} files, {} failed", indexed, failed);
+    if stopped {
+        summary.push_str(&format!(
+            ", {} not processed",
+            file_count - file_events.len()
+        ));
+    }
+    ui.print_error(&summary);
+    std::process::exit(1);
 }
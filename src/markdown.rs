@@ -0,0 +1,166 @@
+use regex::Regex;
+
+pub fn format_markdown(markdown: &str) -> String {
+    let mut result = markdown.to_string();
+
+    // Bold
+    let bold_regex = Regex::new(r"\*\*(.*?)\*\*").unwrap();
+    result = bold_regex
+        .replace_all(&result, |caps: &regex::Captures| {
+            format!("\x1b[1m{}\x1b[0m", &caps[1])
+        })
+        .to_string();
+
+    // Italic
+    let italic_regex = Regex::new(r"\*(.*?)\*").unwrap();
+    result = italic_regex
+        .replace_all(&result, |caps: &regex::Captures| {
+            format!("\x1b[3m{}\x1b[0m", &caps[1])
+        })
+        .to_string();
+
+    // Underline
+    let underline_regex = Regex::new(r"__(.*?)__").unwrap();
+    result = underline_regex
+        .replace_all(&result, |caps: &regex::Captures| {
+            format!("\x1b[4m{}\x1b[0m", &caps[1])
+        })
+        .to_string();
+
+    // Strikethrough
+    let strikethrough_regex = Regex::new(r"~~(.*?)~~").unwrap();
+    result = strikethrough_regex
+        .replace_all(&result, |caps: &regex::Captures| {
+            format!("\x1b[9m{}\x1b[0m", &caps[1])
+        })
+        .to_string();
+
+    // Blockquote
+    let blockquote_regex = Regex::new(r"(> ?.*)").unwrap();
+    result = blockquote_regex
+        .replace_all(&result, |caps: &regex::Captures| {
+            format!("\x1b[3m\x1b[34m\x1b[1m{}\x1b[22m\x1b[0m", &caps[1])
+        })
+        .to_string();
+
+    // Lists (bold magenta number and bullet)
+    let list_regex = Regex::new(r"([\d]+\.|-|\*) (.*)").unwrap();
+    result = list_regex
+        .replace_all(&result, |caps: &regex::Captures| {
+            format!("\x1b[35m\x1b[1m{}\x1b[22m\x1b[0m {}", &caps[1], &caps[2])
+        })
+        .to_string();
+
+    // Block code (black on gray)
+    let block_code_regex = Regex::new(r"(?s)```(\w+)?\n(.*?)\n```").unwrap();
+    result = block_code_regex
+        .replace_all(&result, |caps: &regex::Captures| {
+            let lang = if caps.get(1).is_some() { &caps[1] } else { "" };
+            format!(
+                "\x1b[3m\x1b[1m{}\x1b[22m\x1b[0m\n\x1b[57;107m{}\x1b[0m\n",
+                lang, &caps[2]
+            )
+        })
+        .to_string();
+
+    // Inline code (black on gray)
+    let inline_code_regex = Regex::new(r"`(.*?)`").unwrap();
+    result = inline_code_regex
+        .replace_all(&result, |caps: &regex::Captures| {
+            format!("\x1b[57;107m{}\x1b[0m", &caps[1])
+        })
+        .to_string();
+
+    // Headers (cyan bold)
+    let header_regex = Regex::new(r"(#{1,6}) (.*?)\n").unwrap();
+    result = header_regex
+        .replace_all(&result, |caps: &regex::Captures| {
+            format!("\x1b[36m\x1b[1m{} {}\x1b[22m\x1b[0m\n", &caps[1], &caps[2])
+        })
+        .to_string();
+
+    // Headers with a single line of text followed by 2 or more equal signs
+    let header_equals_regex = Regex::new(r"(.*?\n={2,}\n)").unwrap();
+    result = header_equals_regex
+        .replace_all(&result, |caps: &regex::Captures| {
+            format!("\x1b[36m\x1b[1m{}\x1b[22m\x1b[0m\n", &caps[1])
+        })
+        .to_string();
+
+    // Headers with a single line of text followed by 2 or more dashes
+    let header_dashes_regex = Regex::new(r"(.*?\n-{2,}\n)").unwrap();
+    result = header_dashes_regex
+        .replace_all(&result, |caps: &regex::Captures| {
+            format!("\x1b[36m\x1b[1m{}\x1b[22m\x1b[0m\n", &caps[1])
+        })
+        .to_string();
+
+    // Images (blue underlined)
+    let image_regex = Regex::new(r"!\[(.*?)\]\((.*?)\)").unwrap();
+    result = image_regex
+        .replace_all(&result, |caps: &regex::Captures| {
+            format!(
+                "\x1b[34m{}\x1b[0m (\x1b[34m\x1b[4m{}\x1b[0m)",
+                &caps[1], &caps[2]
+            )
+        })
+        .to_string();
+
+    // Links (blue underlined)
+    let link_regex = Regex::new(r"!?\[(.*?)\]\((.*?)\)").unwrap();
+    result = link_regex
+        .replace_all(&result, |caps: &regex::Captures| {
+            format!(
+                "\x1b[34m{}\x1b[0m (\x1b[34m\x1b[4m{}\x1b[0m)",
+                &caps[1], &caps[2]
+            )
+        })
+        .to_string();
+
+    result
+}
+
+/// Formats markdown as it streams in. Text is emitted one complete line at a time, and fenced
+/// code blocks are held back until they close so they can be formatted as a whole.
+#[derive(Default)]
+pub struct MarkdownStream {
+    pending: String,
+    block: String,
+    in_code_block: bool,
+}
+
+impl MarkdownStream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add streamed text, returning the formatted output that is ready to print
+    pub fn push(&mut self, text: &str) -> String {
+        self.pending.push_str(text);
+
+        let mut output = String::new();
+        while let Some(pos) = self.pending.find('\n') {
+            let line = self.pending.drain(..=pos).collect::<String>();
+            if line.trim_start().starts_with("```") {
+                self.in_code_block = !self.in_code_block;
+            }
+            self.block.push_str(&line);
+
+            if !self.in_code_block {
+                output.push_str(&format_markdown(&self.block));
+                self.block.clear();
+            }
+        }
+
+        output
+    }
+
+    /// Format whatever is left once the stream has ended
+    pub fn finish(&mut self) -> String {
+        let mut rest = std::mem::take(&mut self.block);
+        rest.push_str(&std::mem::take(&mut self.pending));
+        self.in_code_block = false;
+
+        format_markdown(&rest)
+    }
+}
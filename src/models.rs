@@ -0,0 +1,320 @@
+use crate::embeddings::Embedder;
+use fastembed::{
+    EmbeddingModel, RerankInitOptions, RerankerModel, SparseInitOptions, SparseModel,
+    SparseTextEmbedding, TextEmbedding, TextInitOptions, TextRerank,
+};
+use log::{debug, info};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+const DENSE_MODEL: EmbeddingModel = EmbeddingModel::BGESmallENV15;
+const SPARSE_MODEL: SparseModel = SparseModel::SPLADEPPV1;
+const RERANKER_MODEL: RerankerModel = RerankerModel::JINARerankerV1TurboEn;
+
+/// BGE's and mxbai's instruction for retrieval queries; their passages take no prefix
+const RETRIEVAL_INSTRUCTION: &str = "Represent this sentence for searching relevant passages: ";
+
+/// Text a model was trained to see in front of queries and indexed passages. Embedding one
+/// side without its prefix puts it in a different region of the vector space.
+#[derive(Debug, Clone)]
+pub struct Prefixes {
+    pub query: String,
+    pub passage: String,
+}
+
+impl Prefixes {
+    const NONE: Self = Self {
+        query: String::new(),
+        passage: String::new(),
+    };
+
+    fn new(query: &str, passage: &str) -> Self {
+        Self {
+            query: query.to_string(),
+            passage: passage.to_string(),
+        }
+    }
+
+    pub fn query(&self, text: &str) -> String {
+        format!("{}{}", self.query, text)
+    }
+
+    pub fn passage(&self, text: &str) -> String {
+        format!("{}{}", self.passage, text)
+    }
+}
+
+/// The prefixes each dense model family's model card asks for
+fn prefixes_for(model: &EmbeddingModel) -> Prefixes {
+    use EmbeddingModel::*;
+
+    match model {
+        BGESmallENV15 | BGESmallENV15Q | BGEBaseENV15 | BGEBaseENV15Q | BGELargeENV15
+        | BGELargeENV15Q | MxbaiEmbedLargeV1 | MxbaiEmbedLargeV1Q => {
+            Prefixes::new(RETRIEVAL_INSTRUCTION, "")
+        }
+        BGESmallZHV15 | BGELargeZHV15 => {
+            Prefixes::new("为这个句子生成表示以用于检索相关文章：", "")
+        }
+        MultilingualE5Small | MultilingualE5Base | MultilingualE5Large => {
+            Prefixes::new("query: ", "passage: ")
+        }
+        NomicEmbedTextV1 | NomicEmbedTextV15 | NomicEmbedTextV15Q => {
+            Prefixes::new("search_query: ", "search_document: ")
+        }
+        _ => Prefixes::NONE,
+    }
+}
+
+/// Prefixes for the dense model
+pub fn dense_prefixes() -> Prefixes {
+    prefixes_for(&DENSE_MODEL)
+}
+
+/// Hugging Face name of the dense model, e.g. for a server hosting the same model
+pub fn dense_model_name() -> Result<String, Box<dyn std::error::Error>> {
+    Ok(ModelFiles::dense()?.repo)
+}
+
+/// Hugging Face name of the sparse model
+pub fn sparse_model_name() -> String {
+    ModelFiles::sparse().repo
+}
+
+/// SPLADE is trained on raw text, where a prefix only adds noise terms
+pub const SPARSE_PREFIXES: Prefixes = Prefixes::NONE;
+
+/// Tokenizer files every model repository provides next to its ONNX file
+const TOKENIZER_FILES: &[&str] = &[
+    "tokenizer.json",
+    "config.json",
+    "special_tokens_map.json",
+    "tokenizer_config.json",
+];
+
+/// One of the models used by search and the files it needs from its Hugging Face repository
+pub struct ModelFiles {
+    pub role: &'static str,
+    pub repo: String,
+    files: Vec<String>,
+}
+
+impl ModelFiles {
+    fn new(role: &'static str, repo: String, model_file: String, additional: Vec<String>) -> Self {
+        let mut files = vec![model_file];
+        files.extend(additional);
+        files.extend(TOKENIZER_FILES.iter().map(|file| file.to_string()));
+        Self { role, repo, files }
+    }
+
+    fn dense() -> Result<Self, Box<dyn std::error::Error>> {
+        let info = TextEmbedding::get_model_info(&DENSE_MODEL)?;
+        Ok(Self::new(
+            "dense embedding",
+            info.model_code.clone(),
+            info.model_file.clone(),
+            info.additional_files.clone(),
+        ))
+    }
+
+    fn sparse() -> Self {
+        let info = SparseTextEmbedding::get_model_info(&SPARSE_MODEL);
+        Self::new(
+            "sparse embedding",
+            info.model_code,
+            info.model_file,
+            info.additional_files,
+        )
+    }
+
+    fn reranker() -> Self {
+        let info = TextRerank::get_model_info(&RERANKER_MODEL);
+        Self::new(
+            "reranker",
+            info.model_code,
+            info.model_file,
+            info.additional_files,
+        )
+    }
+}
+
+/// Where models are downloaded to and loaded from
+#[derive(Clone)]
+pub struct ModelCache {
+    dir: PathBuf,
+    offline: bool,
+}
+
+/// The per-user cache directory, so models are shared by every working directory
+fn default_cache_dir() -> PathBuf {
+    let base = env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("LOCALAPPDATA").map(PathBuf::from))
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")));
+
+    match base {
+        Some(base) => base.join("ssc").join("models"),
+        // fastembed's own default, relative to the working directory
+        None => PathBuf::from(fastembed::get_cache_dir()),
+    }
+}
+
+impl ModelCache {
+    /// Read `MODEL_CACHE_DIR` (or fastembed's `FASTEMBED_CACHE_DIR`) and `MODEL_OFFLINE`
+    pub fn from_env() -> Self {
+        let dir = env::var_os("MODEL_CACHE_DIR")
+            .or_else(|| env::var_os("FASTEMBED_CACHE_DIR"))
+            .map(PathBuf::from)
+            .unwrap_or_else(default_cache_dir);
+        let offline = env::var("MODEL_OFFLINE")
+            .is_ok_and(|value| matches!(value.to_lowercase().as_str(), "1" | "true" | "yes"));
+
+        Self { dir, offline }
+    }
+
+    /// The same cache, downloading missing files even if MODEL_OFFLINE is set
+    pub fn online(self) -> Self {
+        Self {
+            offline: false,
+            ..self
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Every model search uses
+    pub fn models(&self) -> Result<Vec<ModelFiles>, Box<dyn std::error::Error>> {
+        Ok(vec![
+            ModelFiles::dense()?,
+            ModelFiles::sparse(),
+            ModelFiles::reranker(),
+        ])
+    }
+
+    /// Path of a file in the Hugging Face cache layout fastembed downloads into:
+    /// `models--<org>--<name>/snapshots/<commit from refs/main>/<file>`
+    fn cached_file(&self, repo: &str, file: &str) -> Option<PathBuf> {
+        let repo_dir = self
+            .dir
+            .join(format!("models--{}", repo.replace('/', "--")));
+        let commit = fs::read_to_string(repo_dir.join("refs").join("main")).ok()?;
+        let path = repo_dir.join("snapshots").join(commit.trim()).join(file);
+        path.is_file().then_some(path)
+    }
+
+    /// Files of the model that haven't been downloaded yet
+    pub fn missing_files(&self, model: &ModelFiles) -> Vec<String> {
+        model
+            .files
+            .iter()
+            .filter(|file| self.cached_file(&model.repo, file).is_none())
+            .cloned()
+            .collect()
+    }
+
+    /// Load a model through `load`, which downloads anything missing unless offline
+    fn load<T>(
+        &self,
+        model: ModelFiles,
+        load: impl FnOnce(PathBuf) -> Result<T, fastembed::Error>,
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        if self.offline {
+            let missing = self.missing_files(&model);
+            if !missing.is_empty() {
+                return Err(format!(
+                    "MODEL_OFFLINE is set but the {} model {} is not in {} (missing {}). \
+                     Run `ssc models download` with internet access, or copy the model cache \
+                     from a machine that has it",
+                    model.role,
+                    model.repo,
+                    self.dir.display(),
+                    missing.join(", ")
+                )
+                .into());
+            }
+        }
+
+        info!("Loading the {} model {}", model.role, model.repo);
+        let start = Instant::now();
+        let loaded = load(self.dir.clone()).map_err(|e| {
+            format!(
+                "Failed to load the {} model {} into {}: {}. Check the internet connection, \
+                 or run `ssc models download` first",
+                model.role,
+                model.repo,
+                self.dir.display(),
+                e
+            )
+            .into()
+        });
+        debug!("Loading the {} model: {:?}", model.role, start.elapsed());
+        loaded
+    }
+
+    pub fn load_dense(&self) -> Result<TextEmbedding, Box<dyn std::error::Error>> {
+        self.load(ModelFiles::dense()?, |dir| {
+            TextEmbedding::try_new(TextInitOptions::new(DENSE_MODEL).with_cache_dir(dir))
+        })
+    }
+
+    pub fn load_sparse(&self) -> Result<SparseTextEmbedding, Box<dyn std::error::Error>> {
+        self.load(ModelFiles::sparse(), |dir| {
+            SparseTextEmbedding::try_new(SparseInitOptions::new(SPARSE_MODEL).with_cache_dir(dir))
+        })
+    }
+
+    pub fn load_reranker(&self) -> Result<TextRerank, Box<dyn std::error::Error>> {
+        self.load(ModelFiles::reranker(), |dir| {
+            TextRerank::try_new(RerankInitOptions::new(RERANKER_MODEL).with_cache_dir(dir))
+        })
+    }
+}
+
+/// A model that is only loaded the first time it is used
+pub struct LazyModel<T> {
+    cache: ModelCache,
+    load: fn(&ModelCache) -> Result<T, Box<dyn std::error::Error>>,
+    model: Option<T>,
+}
+
+impl<T> LazyModel<T> {
+    fn new(
+        cache: &ModelCache,
+        load: fn(&ModelCache) -> Result<T, Box<dyn std::error::Error>>,
+    ) -> Self {
+        Self {
+            cache: cache.clone(),
+            load,
+            model: None,
+        }
+    }
+
+    pub fn get(&mut self) -> Result<&mut T, Box<dyn std::error::Error>> {
+        if self.model.is_none() {
+            self.model = Some((self.load)(&self.cache)?);
+        }
+        Ok(self.model.as_mut().expect("model was loaded above"))
+    }
+}
+
+/// The embedding and reranking models used by search. Each is loaded when a search first
+/// needs it, so e.g. a dense-only search never loads SPLADE or the reranker.
+pub struct SearchModels {
+    pub dense: LazyModel<Embedder>,
+    pub sparse: LazyModel<SparseTextEmbedding>,
+    pub reranker: LazyModel<TextRerank>,
+}
+
+impl SearchModels {
+    pub fn new(cache: &ModelCache) -> Self {
+        Self {
+            dense: LazyModel::new(cache, Embedder::from_env),
+            sparse: LazyModel::new(cache, ModelCache::load_sparse),
+            reranker: LazyModel::new(cache, ModelCache::load_reranker),
+        }
+    }
+}
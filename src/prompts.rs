@@ -0,0 +1,221 @@
+use crate::ai::Message;
+use crate::vector_store::SearchResult;
+use regex::{Captures, Regex};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Placeholders that can appear in a template, replaced when the prompt is rendered
+const PLACEHOLDERS: &[&str] = &["query", "sources", "source_count", "files", "date"];
+
+const DEFAULT_TEMPLATE: &str = "[system]
+You are a helpful assistant that analyzes search results from a document database and provides comprehensive answers based on the information found.
+[user]
+Based on the following search results from a document database, please provide a comprehensive answer to the user's query.
+
+User Query: {query}
+
+Search Results:
+{sources}
+
+Please provide a detailed answer based on the information found in the search results. If the search results don't contain enough information to fully answer the query, please indicate what additional information might be needed.";
+
+const SUMMARIZE_TEMPLATE: &str = "[system]
+You are an assistant that writes concise, faithful summaries of documents. Only use information from the provided sources.
+[user]
+Summarize what the following {source_count} excerpts say about: {query}
+
+Sources:
+{sources}
+
+Write a short summary followed by the key points as a bulleted list. Cite the supporting source numbers in brackets, e.g. [Source 2], after each point.";
+
+const COMPARE_TEMPLATE: &str = "[system]
+You are an analyst who compares documents carefully, pointing out where they agree, differ or contradict each other. Only use information from the provided sources.
+[user]
+Compare how the following documents address: {query}
+
+Documents: {files}
+
+Sources:
+{sources}
+
+Describe the points the documents agree on, where they differ and any contradictions, citing source numbers in brackets. Finish with a table summarizing each document's position.";
+
+/// Added to the system message with --strict
+const GROUNDING_INSTRUCTION: &str = "Answer only from the provided search results, never from outside knowledge. If they don't contain the answer, say that it was not found in the documents.";
+
+/// Templates that are always available, even without a prompts directory
+const BUILT_IN: &[(&str, &str)] = &[
+    ("default", DEFAULT_TEMPLATE),
+    ("summarize", SUMMARIZE_TEMPLATE),
+    ("compare", COMPARE_TEMPLATE),
+];
+
+/// A system message and user prompt with `{placeholder}`s for the query and sources
+#[derive(Debug, Clone)]
+pub struct PromptTemplate {
+    system: String,
+    user: String,
+}
+
+fn placeholder_regex() -> Regex {
+    Regex::new(r"\{([a-z_]+)\}").unwrap()
+}
+
+/// Directory searched for `<name>.txt` templates, `PROMPTS_DIR` or `./prompts`
+fn prompts_dir() -> PathBuf {
+    std::env::var("PROMPTS_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("prompts"))
+}
+
+impl PromptTemplate {
+    /// Load a template by name or path. Names are looked up as `<name>.txt` in the prompts
+    /// directory first, so a file there can also override a built-in template.
+    pub fn load(name: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = Path::new(name);
+        let file = if path.is_file() {
+            Some(path.to_path_buf())
+        } else {
+            Some(prompts_dir().join(format!("{}.txt", name))).filter(|path| path.is_file())
+        };
+
+        if let Some(file) = file {
+            let content = fs::read_to_string(&file)?;
+            return Self::parse(&content).map_err(|e| format!("{}: {}", file.display(), e).into());
+        }
+
+        match BUILT_IN.iter().find(|(builtin, _)| *builtin == name) {
+            Some((_, content)) => Self::parse(content).map_err(|e| e.into()),
+            None => Err(format!(
+                "Unknown prompt '{}'. Use a file path, a .txt file in {}, or one of: {}",
+                name,
+                prompts_dir().display(),
+                BUILT_IN
+                    .iter()
+                    .map(|(name, _)| *name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+            .into()),
+        }
+    }
+
+    /// Parse a template file: a `[system]` section followed by a `[user]` section, each
+    /// marker on a line of its own. Without markers the whole file is the user prompt.
+    fn parse(content: &str) -> Result<Self, String> {
+        // Text before any marker belongs to the user prompt
+        let mut sections = [String::new(), String::new()];
+        let mut current = 1;
+
+        for line in content.lines() {
+            match line.trim() {
+                "[system]" => current = 0,
+                "[user]" => current = 1,
+                _ => {
+                    sections[current].push_str(line);
+                    sections[current].push('\n');
+                }
+            }
+        }
+        let [system, user] = sections;
+
+        let template = Self {
+            system: system.trim().to_string(),
+            user: user.trim().to_string(),
+        };
+        if template.user.is_empty() {
+            return Err("prompt template has no [user] section".to_string());
+        }
+
+        for caps in
+            placeholder_regex().captures_iter(&format!("{}\n{}", template.system, template.user))
+        {
+            if !PLACEHOLDERS.contains(&&caps[1]) {
+                return Err(format!(
+                    "unknown placeholder {{{}}}, expected one of: {}",
+                    &caps[1],
+                    PLACEHOLDERS
+                        .iter()
+                        .map(|p| format!("{{{}}}", p))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+            }
+        }
+
+        Ok(template)
+    }
+
+    /// The same template, told to answer only from the search results
+    pub fn grounded(mut self) -> Self {
+        self.system = if self.system.is_empty() {
+            GROUNDING_INSTRUCTION.to_string()
+        } else {
+            format!("{}\n\n{}", self.system, GROUNDING_INSTRUCTION)
+        };
+        self
+    }
+
+    fn render(&self, text: &str, query: &str, search_results: &[SearchResult]) -> String {
+        placeholder_regex()
+            .replace_all(text, |caps: &Captures| match &caps[1] {
+                "query" => query.to_string(),
+                "sources" => format_sources(search_results),
+                "source_count" => search_results.len().to_string(),
+                "files" => search_results
+                    .iter()
+                    .map(|result| result.file_path.as_str())
+                    .collect::<BTreeSet<_>>()
+                    .into_iter()
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                "date" => {
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs();
+                    crate::cli_ui::format_timestamp(now)[..10].to_string()
+                }
+                _ => caps[0].to_string(),
+            })
+            .into_owned()
+    }
+
+    /// The system message (if any) and user prompt for a single question
+    pub fn messages(&self, query: &str, search_results: &[SearchResult]) -> Vec<Message> {
+        let mut messages = Vec::new();
+        if !self.system.is_empty() {
+            messages.push(Message::system(self.render(
+                &self.system,
+                query,
+                search_results,
+            )));
+        }
+        messages.push(Message::user(self.render(
+            &self.user,
+            query,
+            search_results,
+        )));
+        messages
+    }
+}
+
+fn format_sources(search_results: &[SearchResult]) -> String {
+    search_results
+        .iter()
+        .enumerate()
+        .map(|(i, result)| {
+            format!(
+                "[Source {}] File: {} (Score: {:.4})\nContent: {}\n",
+                i + 1,
+                result.file_name,
+                result.similarity_score,
+                result.chunk_content
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
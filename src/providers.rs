@@ -0,0 +1,903 @@
+use crate::ai::{ChatCompletionRequest, LlmConfig, LlmError, Message};
+use serde::{Deserialize, Serialize};
+
+/// A complete, non-streamed response
+pub struct Completion {
+    pub content: Option<String>,
+    /// Why generation stopped; `length` whenever the token limit was hit
+    pub finish_reason: String,
+}
+
+/// What one line of a streamed response contributes
+#[derive(Default)]
+pub struct StreamEvent {
+    pub token: Option<String>,
+    pub finish_reason: Option<String>,
+    pub done: bool,
+}
+
+/// Translates chat requests and responses to and from one provider's HTTP API.
+/// `LlmClient` takes care of sending them, retries and reading streams.
+pub trait LlmProvider: Send + Sync {
+    /// Endpoint for a request; some APIs use a different one for streaming
+    fn url(&self, config: &LlmConfig, _stream: bool) -> String {
+        config.url.clone()
+    }
+
+    /// Authentication and any other headers the API requires
+    fn headers(&self, config: &LlmConfig) -> Vec<(&'static str, String)>;
+
+    fn request_body(
+        &self,
+        request: &ChatCompletionRequest,
+    ) -> Result<serde_json::Value, serde_json::Error>;
+
+    fn parse_response(&self, body: &str) -> Result<Completion, LlmError>;
+
+    /// Parse one line of a streamed response, `None` for lines that carry nothing
+    fn parse_stream_line(&self, line: &str) -> Result<Option<StreamEvent>, LlmError>;
+}
+
+/// The payload of a server-sent `data:` line
+fn sse_data(line: &str) -> Option<&str> {
+    line.trim().strip_prefix("data:").map(str::trim)
+}
+
+fn bearer(config: &LlmConfig) -> Vec<(&'static str, String)> {
+    config
+        .api_key
+        .iter()
+        .map(|api_key| ("Authorization", format!("Bearer {}", api_key)))
+        .collect()
+}
+
+// OpenAI-compatible chat completions, also served by llama.cpp and Ollama's /v1 path
+
+#[derive(Debug, Deserialize)]
+pub struct ChatCompletionResponse {
+    pub choices: Vec<Choice>,
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Choice {
+    pub message: ResponseMessage,
+    pub finish_reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResponseMessage {
+    pub role: String,
+    pub content: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: i32,
+    pub completion_tokens: i32,
+    pub total_tokens: i32,
+}
+
+/// One server-sent event of a streamed chat completion
+#[derive(Debug, Deserialize)]
+pub struct ChatCompletionChunk {
+    pub choices: Vec<ChunkChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChunkChoice {
+    pub delta: Delta,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Delta {
+    pub content: Option<String>,
+}
+
+pub struct OpenAiProvider;
+
+impl LlmProvider for OpenAiProvider {
+    fn headers(&self, config: &LlmConfig) -> Vec<(&'static str, String)> {
+        let mut headers = bearer(config);
+        if let Some(organization) = &config.organization {
+            headers.push(("OpenAI-Organization", organization.clone()));
+        }
+        headers
+    }
+
+    fn request_body(
+        &self,
+        request: &ChatCompletionRequest,
+    ) -> Result<serde_json::Value, serde_json::Error> {
+        serde_json::to_value(request)
+    }
+
+    fn parse_response(&self, body: &str) -> Result<Completion, LlmError> {
+        let response: ChatCompletionResponse = serde_json::from_str(body)?;
+        let choice = response
+            .choices
+            .into_iter()
+            .next()
+            .ok_or(LlmError::EmptyResponse)?;
+
+        Ok(Completion {
+            content: choice.message.content,
+            finish_reason: choice.finish_reason,
+        })
+    }
+
+    fn parse_stream_line(&self, line: &str) -> Result<Option<StreamEvent>, LlmError> {
+        let Some(data) = sse_data(line) else {
+            return Ok(None);
+        };
+        if data == "[DONE]" {
+            return Ok(Some(StreamEvent {
+                done: true,
+                ..Default::default()
+            }));
+        }
+
+        let chunk: ChatCompletionChunk = serde_json::from_str(data)?;
+        Ok(chunk.choices.into_iter().next().map(|choice| StreamEvent {
+            token: choice.delta.content,
+            finish_reason: choice.finish_reason,
+            done: false,
+        }))
+    }
+}
+
+// Ollama's native /api/chat
+
+#[derive(Debug, Serialize)]
+struct OllamaChatRequest<'a> {
+    model: &'a str,
+    messages: &'a [Message],
+    stream: bool,
+    options: OllamaOptions<'a>,
+}
+
+#[derive(Debug, Serialize)]
+struct OllamaOptions<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_predict: Option<i32>,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    stop: &'a [String],
+}
+
+/// An Ollama `/api/chat` response, or one line of a streamed one
+#[derive(Debug, Deserialize)]
+struct OllamaChatResponse {
+    message: Option<OllamaMessage>,
+    #[serde(default)]
+    done: bool,
+    done_reason: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaMessage {
+    content: String,
+}
+
+pub struct OllamaProvider;
+
+impl LlmProvider for OllamaProvider {
+    // Ollama itself has no auth, but a key is passed on for servers behind a proxy
+    fn headers(&self, config: &LlmConfig) -> Vec<(&'static str, String)> {
+        bearer(config)
+    }
+
+    fn request_body(
+        &self,
+        request: &ChatCompletionRequest,
+    ) -> Result<serde_json::Value, serde_json::Error> {
+        serde_json::to_value(OllamaChatRequest {
+            model: &request.model,
+            messages: &request.messages,
+            // Ollama streams unless told otherwise
+            stream: request.stream.unwrap_or(false),
+            options: OllamaOptions {
+                temperature: request.temperature,
+                top_p: request.top_p,
+                num_predict: request.max_tokens,
+                stop: &request.stop,
+            },
+        })
+    }
+
+    fn parse_response(&self, body: &str) -> Result<Completion, LlmError> {
+        let response: OllamaChatResponse = serde_json::from_str(body)?;
+
+        Ok(Completion {
+            content: response
+                .message
+                .map(|message| message.content)
+                .filter(|content| !content.is_empty()),
+            finish_reason: response.done_reason.unwrap_or_else(|| "stop".to_string()),
+        })
+    }
+
+    fn parse_stream_line(&self, line: &str) -> Result<Option<StreamEvent>, LlmError> {
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(None);
+        }
+
+        let chunk: OllamaChatResponse = serde_json::from_str(line)?;
+        if let Some(error) = chunk.error {
+            return Err(LlmError::Stream(error));
+        }
+
+        Ok(Some(StreamEvent {
+            token: chunk.message.map(|message| message.content),
+            finish_reason: chunk.done_reason,
+            done: chunk.done,
+        }))
+    }
+}
+
+// Anthropic Messages API
+
+#[derive(Debug, Serialize)]
+struct AnthropicRequest<'a> {
+    model: &'a str,
+    /// Anthropic takes the system prompt separately from the conversation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    messages: Vec<&'a Message>,
+    max_tokens: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    stop_sequences: &'a [String],
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicResponse {
+    content: Vec<AnthropicContent>,
+    stop_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicContent {
+    text: Option<String>,
+}
+
+/// Streamed responses are a sequence of typed server-sent events
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AnthropicEvent {
+    ContentBlockDelta {
+        delta: AnthropicContent,
+    },
+    MessageDelta {
+        delta: AnthropicMessageDelta,
+    },
+    MessageStop,
+    Error {
+        error: AnthropicError,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicMessageDelta {
+    stop_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicError {
+    message: String,
+}
+
+fn anthropic_stop_reason(stop_reason: &str) -> String {
+    match stop_reason {
+        "max_tokens" => "length".to_string(),
+        other => other.to_string(),
+    }
+}
+
+pub struct AnthropicProvider;
+
+impl LlmProvider for AnthropicProvider {
+    fn headers(&self, config: &LlmConfig) -> Vec<(&'static str, String)> {
+        let mut headers = vec![("anthropic-version", "2023-06-01".to_string())];
+        if let Some(api_key) = &config.api_key {
+            headers.push(("x-api-key", api_key.clone()));
+        }
+        headers
+    }
+
+    fn request_body(
+        &self,
+        request: &ChatCompletionRequest,
+    ) -> Result<serde_json::Value, serde_json::Error> {
+        let (system, messages): (Vec<&Message>, Vec<&Message>) = request
+            .messages
+            .iter()
+            .partition(|message| message.role == "system");
+        let system = system
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect::<Vec<_>>()
+            .join("\n\n");
+
+        serde_json::to_value(AnthropicRequest {
+            model: &request.model,
+            system: Some(system).filter(|system| !system.is_empty()),
+            messages,
+            // Required by the Messages API
+            max_tokens: request.max_tokens.unwrap_or(4096),
+            temperature: request.temperature,
+            top_p: request.top_p,
+            stop_sequences: &request.stop,
+            stream: request.stream.unwrap_or(false),
+        })
+    }
+
+    fn parse_response(&self, body: &str) -> Result<Completion, LlmError> {
+        let response: AnthropicResponse = serde_json::from_str(body)?;
+        let content = response
+            .content
+            .into_iter()
+            .filter_map(|block| block.text)
+            .collect::<String>();
+
+        Ok(Completion {
+            content: Some(content).filter(|content| !content.is_empty()),
+            finish_reason: anthropic_stop_reason(response.stop_reason.as_deref().unwrap_or("")),
+        })
+    }
+
+    fn parse_stream_line(&self, line: &str) -> Result<Option<StreamEvent>, LlmError> {
+        // `event:` lines repeat the type that is also in the data
+        let Some(data) = sse_data(line) else {
+            return Ok(None);
+        };
+
+        Ok(match serde_json::from_str(data)? {
+            AnthropicEvent::ContentBlockDelta { delta } => Some(StreamEvent {
+                token: delta.text,
+                ..Default::default()
+            }),
+            AnthropicEvent::MessageDelta { delta } => Some(StreamEvent {
+                finish_reason: delta.stop_reason.as_deref().map(anthropic_stop_reason),
+                ..Default::default()
+            }),
+            AnthropicEvent::MessageStop => Some(StreamEvent {
+                done: true,
+                ..Default::default()
+            }),
+            AnthropicEvent::Error { error } => return Err(LlmError::Stream(error.message)),
+            AnthropicEvent::Other => None,
+        })
+    }
+}
+
+// Google Gemini generateContent
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiRequest {
+    contents: Vec<GeminiContent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_instruction: Option<GeminiContent>,
+    generation_config: GeminiGenerationConfig,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GeminiContent {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<String>,
+    #[serde(default)]
+    parts: Vec<GeminiPart>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GeminiPart {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiGenerationConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_output_tokens: Option<i32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop_sequences: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GeminiResponse {
+    #[serde(default)]
+    candidates: Vec<GeminiCandidate>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiCandidate {
+    content: Option<GeminiContent>,
+    finish_reason: Option<String>,
+}
+
+impl GeminiCandidate {
+    fn text(&self) -> Option<String> {
+        let text = self
+            .content
+            .iter()
+            .flat_map(|content| &content.parts)
+            .filter_map(|part| part.text.as_deref())
+            .collect::<String>();
+        Some(text).filter(|text| !text.is_empty())
+    }
+
+    fn finish_reason(&self) -> Option<String> {
+        self.finish_reason.as_deref().map(|reason| match reason {
+            "MAX_TOKENS" => "length".to_string(),
+            other => other.to_lowercase(),
+        })
+    }
+}
+
+pub struct GeminiProvider;
+
+impl LlmProvider for GeminiProvider {
+    /// `config.url` is the API base; the model and method are part of the path
+    fn url(&self, config: &LlmConfig, stream: bool) -> String {
+        let method = if stream {
+            "streamGenerateContent?alt=sse"
+        } else {
+            "generateContent"
+        };
+        format!(
+            "{}/models/{}:{}",
+            config.url.trim_end_matches('/'),
+            config.model,
+            method
+        )
+    }
+
+    fn headers(&self, config: &LlmConfig) -> Vec<(&'static str, String)> {
+        config
+            .api_key
+            .iter()
+            .map(|api_key| ("x-goog-api-key", api_key.clone()))
+            .collect()
+    }
+
+    fn request_body(
+        &self,
+        request: &ChatCompletionRequest,
+    ) -> Result<serde_json::Value, serde_json::Error> {
+        let text_content = |role: Option<&str>, text: &str| GeminiContent {
+            role: role.map(str::to_string),
+            parts: vec![GeminiPart {
+                text: Some(text.to_string()),
+            }],
+        };
+
+        let system = request
+            .messages
+            .iter()
+            .filter(|message| message.role == "system")
+            .map(|message| message.content.as_str())
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        let contents = request
+            .messages
+            .iter()
+            .filter(|message| message.role != "system")
+            .map(|message| {
+                let role = if message.role == "assistant" {
+                    "model"
+                } else {
+                    "user"
+                };
+                text_content(Some(role), &message.content)
+            })
+            .collect();
+
+        serde_json::to_value(GeminiRequest {
+            contents,
+            system_instruction: Some(system)
+                .filter(|system| !system.is_empty())
+                .map(|system| text_content(None, &system)),
+            generation_config: GeminiGenerationConfig {
+                temperature: request.temperature,
+                top_p: request.top_p,
+                max_output_tokens: request.max_tokens,
+                stop_sequences: request.stop.clone(),
+            },
+        })
+    }
+
+    fn parse_response(&self, body: &str) -> Result<Completion, LlmError> {
+        let response: GeminiResponse = serde_json::from_str(body)?;
+        // No candidates at all means the prompt itself was blocked
+        let candidate = response
+            .candidates
+            .into_iter()
+            .next()
+            .ok_or(LlmError::EmptyResponse)?;
+
+        Ok(Completion {
+            content: candidate.text(),
+            finish_reason: candidate.finish_reason().unwrap_or_default(),
+        })
+    }
+
+    // Every event carries a partial response; the stream simply ends after the last one
+    fn parse_stream_line(&self, line: &str) -> Result<Option<StreamEvent>, LlmError> {
+        let Some(data) = sse_data(line) else {
+            return Ok(None);
+        };
+
+        let chunk: GeminiResponse = serde_json::from_str(data)?;
+        Ok(chunk.candidates.first().map(|candidate| StreamEvent {
+            token: candidate.text(),
+            finish_reason: candidate.finish_reason(),
+            done: false,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ai::{LlmBackend, LlmClient, LlmConfig, LlmError, Message};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    /// A request as the mock server received it
+    struct Received {
+        path: String,
+        headers: Vec<(String, String)>,
+        body: serde_json::Value,
+    }
+
+    impl Received {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(header, _)| header.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        }
+    }
+
+    /// Answer a single request on a free local port with `status` and `body`. Returns the
+    /// server's address and the request it received.
+    async fn mock_server(
+        status: u16,
+        content_type: &'static str,
+        body: &'static str,
+    ) -> (String, JoinHandle<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            let header_end = loop {
+                let read = socket.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+                if let Some(pos) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break pos + 4;
+                }
+            };
+
+            let head = String::from_utf8_lossy(&request[..header_end]).to_string();
+            let mut lines = head.lines();
+            let path = lines.next().unwrap().split(' ').nth(1).unwrap().to_string();
+            let headers = lines
+                .filter_map(|line| line.split_once(':'))
+                .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+                .collect::<Vec<_>>();
+            let length = headers
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                .map_or(0, |(_, value)| value.parse().unwrap());
+            while request.len() < header_end + length {
+                let read = socket.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+            }
+
+            let response = format!(
+                "HTTP/1.1 {} Mock\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                content_type,
+                body.len(),
+                body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+
+            Received {
+                path,
+                headers,
+                body: serde_json::from_slice(&request[header_end..]).unwrap(),
+            }
+        });
+
+        (address, server)
+    }
+
+    fn client(backend: LlmBackend, url: String) -> LlmClient {
+        LlmClient::new(LlmConfig {
+            backend,
+            api_key: Some("test-key".to_string()),
+            url,
+            model: "test-model".to_string(),
+            temperature: 0.5,
+            max_tokens: 100,
+            top_p: None,
+            stop: vec!["END".to_string()],
+            timeout: Some(Duration::from_secs(5)),
+            max_retries: 0,
+            context_budget: 8000,
+            organization: None,
+            headers: Vec::new(),
+        })
+        .unwrap()
+    }
+
+    fn conversation() -> Vec<Message> {
+        vec![
+            Message::system("Answer briefly."),
+            Message::user("What is Rust?"),
+        ]
+    }
+
+    async fn stream(client: &LlmClient) -> (String, Vec<String>) {
+        let mut tokens = Vec::new();
+        let content = client
+            .chat_stream(conversation(), |token| tokens.push(token.to_string()))
+            .await
+            .unwrap();
+        (content, tokens)
+    }
+
+    #[tokio::test]
+    async fn openai_completion() {
+        let (address, server) = mock_server(
+            200,
+            "application/json",
+            r#"{"choices":[{"message":{"role":"assistant","content":"A language."},"finish_reason":"stop"}]}"#,
+        )
+        .await;
+        let client = client(
+            LlmBackend::OpenAi,
+            format!("{}/v1/chat/completions", address),
+        );
+
+        assert_eq!(client.chat(conversation()).await.unwrap(), "A language.");
+
+        let request = server.await.unwrap();
+        assert_eq!(request.path, "/v1/chat/completions");
+        assert_eq!(request.header("authorization"), Some("Bearer test-key"));
+        assert_eq!(request.body["model"], "test-model");
+        assert_eq!(request.body["max_tokens"], 100);
+        assert_eq!(request.body["stop"][0], "END");
+        assert_eq!(request.body["messages"][0]["role"], "system");
+        assert_eq!(request.body["messages"][1]["content"], "What is Rust?");
+    }
+
+    #[tokio::test]
+    async fn openai_stream() {
+        let (address, server) = mock_server(
+            200,
+            "text/event-stream",
+            concat!(
+                "data: {\"choices\":[{\"delta\":{\"content\":\"A \"},\"finish_reason\":null}]}\n\n",
+                "data: {\"choices\":[{\"delta\":{\"content\":\"language.\"},\"finish_reason\":\"stop\"}]}\n\n",
+                "data: [DONE]\n\n",
+            ),
+        )
+        .await;
+        let client = client(
+            LlmBackend::OpenAi,
+            format!("{}/v1/chat/completions", address),
+        );
+
+        let (content, tokens) = stream(&client).await;
+        assert_eq!(content, "A language.");
+        assert_eq!(tokens, ["A ", "language."]);
+        assert_eq!(server.await.unwrap().body["stream"], true);
+    }
+
+    #[tokio::test]
+    async fn ollama_completion() {
+        let (address, server) = mock_server(
+            200,
+            "application/json",
+            r#"{"message":{"role":"assistant","content":"A language."},"done":true,"done_reason":"stop"}"#,
+        )
+        .await;
+        let client = client(LlmBackend::Ollama, format!("{}/api/chat", address));
+
+        assert_eq!(client.chat(conversation()).await.unwrap(), "A language.");
+
+        let request = server.await.unwrap();
+        assert_eq!(request.path, "/api/chat");
+        assert_eq!(request.header("authorization"), Some("Bearer test-key"));
+        assert_eq!(request.body["stream"], false);
+        assert_eq!(request.body["options"]["num_predict"], 100);
+        assert_eq!(request.body["options"]["stop"][0], "END");
+        assert_eq!(request.body["messages"][0]["role"], "system");
+    }
+
+    #[tokio::test]
+    async fn ollama_stream() {
+        let (address, server) = mock_server(
+            200,
+            "application/x-ndjson",
+            concat!(
+                "{\"message\":{\"content\":\"A \"},\"done\":false}\n",
+                "{\"message\":{\"content\":\"language.\"},\"done\":false}\n",
+                "{\"message\":{\"content\":\"\"},\"done\":true,\"done_reason\":\"stop\"}\n",
+            ),
+        )
+        .await;
+        let client = client(LlmBackend::Ollama, format!("{}/api/chat", address));
+
+        let (content, tokens) = stream(&client).await;
+        assert_eq!(content, "A language.");
+        assert_eq!(tokens, ["A ", "language."]);
+        assert_eq!(server.await.unwrap().body["stream"], true);
+    }
+
+    #[tokio::test]
+    async fn anthropic_completion() {
+        let (address, server) = mock_server(
+            200,
+            "application/json",
+            r#"{"content":[{"type":"text","text":"A language."}],"stop_reason":"end_turn"}"#,
+        )
+        .await;
+        let client = client(LlmBackend::Anthropic, format!("{}/v1/messages", address));
+
+        assert_eq!(client.chat(conversation()).await.unwrap(), "A language.");
+
+        let request = server.await.unwrap();
+        assert_eq!(request.path, "/v1/messages");
+        assert_eq!(request.header("x-api-key"), Some("test-key"));
+        assert_eq!(request.header("anthropic-version"), Some("2023-06-01"));
+        assert_eq!(request.header("authorization"), None);
+        assert_eq!(request.body["system"], "Answer briefly.");
+        assert_eq!(request.body["messages"].as_array().unwrap().len(), 1);
+        assert_eq!(request.body["messages"][0]["role"], "user");
+        assert_eq!(request.body["max_tokens"], 100);
+        assert_eq!(request.body["stop_sequences"][0], "END");
+    }
+
+    #[tokio::test]
+    async fn anthropic_stream() {
+        let (address, server) = mock_server(
+            200,
+            "text/event-stream",
+            concat!(
+                "event: message_start\n",
+                "data: {\"type\":\"message_start\",\"message\":{}}\n\n",
+                "event: content_block_delta\n",
+                "data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"A \"}}\n\n",
+                "event: content_block_delta\n",
+                "data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"language.\"}}\n\n",
+                "event: message_delta\n",
+                "data: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"end_turn\"}}\n\n",
+                "event: message_stop\n",
+                "data: {\"type\":\"message_stop\"}\n\n",
+            ),
+        )
+        .await;
+        let client = client(LlmBackend::Anthropic, format!("{}/v1/messages", address));
+
+        let (content, tokens) = stream(&client).await;
+        assert_eq!(content, "A language.");
+        assert_eq!(tokens, ["A ", "language."]);
+        assert_eq!(server.await.unwrap().body["stream"], true);
+    }
+
+    #[tokio::test]
+    async fn gemini_completion() {
+        let (address, server) = mock_server(
+            200,
+            "application/json",
+            r#"{"candidates":[{"content":{"role":"model","parts":[{"text":"A language."}]},"finishReason":"STOP"}]}"#,
+        )
+        .await;
+        let client = client(LlmBackend::Gemini, format!("{}/v1beta", address));
+
+        assert_eq!(client.chat(conversation()).await.unwrap(), "A language.");
+
+        let request = server.await.unwrap();
+        assert_eq!(request.path, "/v1beta/models/test-model:generateContent");
+        assert_eq!(request.header("x-goog-api-key"), Some("test-key"));
+        assert_eq!(request.header("authorization"), None);
+        assert_eq!(
+            request.body["systemInstruction"]["parts"][0]["text"],
+            "Answer briefly."
+        );
+        assert_eq!(request.body["contents"].as_array().unwrap().len(), 1);
+        assert_eq!(request.body["contents"][0]["role"], "user");
+        assert_eq!(
+            request.body["contents"][0]["parts"][0]["text"],
+            "What is Rust?"
+        );
+        assert_eq!(request.body["generationConfig"]["maxOutputTokens"], 100);
+        assert_eq!(request.body["generationConfig"]["stopSequences"][0], "END");
+    }
+
+    #[tokio::test]
+    async fn gemini_stream() {
+        let (address, server) = mock_server(
+            200,
+            "text/event-stream",
+            concat!(
+                "data: {\"candidates\":[{\"content\":{\"role\":\"model\",\"parts\":[{\"text\":\"A \"}]}}]}\n\n",
+                "data: {\"candidates\":[{\"content\":{\"role\":\"model\",\"parts\":[{\"text\":\"language.\"}]},\"finishReason\":\"STOP\"}]}\n\n",
+            ),
+        )
+        .await;
+        let client = client(LlmBackend::Gemini, format!("{}/v1beta", address));
+
+        let (content, tokens) = stream(&client).await;
+        assert_eq!(content, "A language.");
+        assert_eq!(tokens, ["A ", "language."]);
+        assert_eq!(
+            server.await.unwrap().path,
+            "/v1beta/models/test-model:streamGenerateContent?alt=sse"
+        );
+    }
+
+    /// Whether an error is the variant a status should map to
+    type Expected = fn(&LlmError) -> bool;
+
+    #[tokio::test]
+    async fn error_statuses() {
+        let cases: [(u16, &'static str, Expected); 5] = [
+            (401, r#"{"error":{"message":"Invalid API key"}}"#, |e| {
+                matches!(e, LlmError::Auth { .. })
+            }),
+            (403, r#"{"error":"forbidden"}"#, |e| {
+                matches!(e, LlmError::Auth { .. })
+            }),
+            (429, r#"{"error":{"message":"Slow down"}}"#, |e| {
+                matches!(e, LlmError::RateLimited { .. })
+            }),
+            (
+                400,
+                r#"{"error":{"message":"This model's maximum context length is 8192 tokens","code":"context_length_exceeded"}}"#,
+                |e| matches!(e, LlmError::ContextLength { .. }),
+            ),
+            (
+                500,
+                "Internal Server Error",
+                |e| matches!(e, LlmError::Api { status, .. } if status.as_u16() == 500),
+            ),
+        ];
+
+        for (status, body, expected) in cases {
+            let (address, _server) = mock_server(status, "application/json", body).await;
+            let client = client(LlmBackend::OpenAi, address);
+
+            let error = client.chat(conversation()).await.unwrap_err();
+            let error = error.downcast_ref::<LlmError>().unwrap();
+            assert!(expected(error), "status {} gave {:?}", status, error);
+        }
+    }
+}
@@ -1,143 +1,533 @@
+use crate::embeddings::env_number;
+use crate::models::SearchModels;
+use crate::vector_store::{
+    embed_dense_query, embed_sparse_query, file_id_for_path, path_filter_prefix, ChunkMetadata,
+    FileMetadata, FusionMethod, IndexedFile, SearchFilter, SearchMode, SearchOptions, SearchResult,
+    VectorStore, DENSE_DIMENSIONS,
+};
+use fastembed::SparseEmbedding;
+use log::{debug, info, warn};
 use qdrant_client::{
+    config::CompressionEncoding,
     qdrant::{
-        CreateCollectionBuilder, Distance, Fusion, NamedVectors, PointStruct, PrefetchQueryBuilder,
-        Query, QueryPointsBuilder, SparseVectorParamsBuilder, SparseVectorsConfigBuilder,
-        UpsertPointsBuilder, Vector, VectorParamsBuilder, VectorsConfigBuilder,
+        BinaryQuantizationBuilder, Condition, CountPointsBuilder, CreateCollectionBuilder,
+        CreateFieldIndexCollectionBuilder, DeletePointsBuilder, Distance, FieldType, Filter,
+        Fusion, GetPointsBuilder, HnswConfigDiffBuilder, Modifier, NamedVectors,
+        PayloadIncludeSelector, PointId, PointStruct, PrefetchQueryBuilder, Query,
+        QueryPointsBuilder, Range, RetrievedPoint, ScalarQuantizationBuilder, ScoredPoint,
+        ScrollPointsBuilder, SparseIndexConfigBuilder, SparseVectorParamsBuilder,
+        SparseVectorsConfigBuilder, UpsertPointsBuilder, Value, Vector, VectorInput,
+        VectorParamsBuilder, VectorsConfigBuilder,
     },
-    Payload, Qdrant,
+    Payload, Qdrant, QdrantError,
 };
-use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::time::Instant;
+use std::env;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct FileMetadata {
-    pub file_path: String,
-    pub file_name: String,
-    pub file_size: u64,
-    pub modified_time: u64,
-    pub content_hash: String,
-    pub markdown_content: Option<String>,
+/// The search filter as payload conditions, applied to both prefetch branches
+fn qdrant_filter(filter: &SearchFilter) -> Option<Filter> {
+    let mut conditions = Vec::new();
+
+    if let Some(prefix) = filter.path_prefix.as_deref().and_then(path_filter_prefix) {
+        conditions.push(Condition::matches("path_prefixes", prefix.to_string()));
+    }
+
+    if !filter.extensions.is_empty() {
+        let extensions = filter
+            .extensions
+            .iter()
+            .map(|ext| ext.trim_start_matches('.').to_lowercase())
+            .collect::<Vec<_>>();
+        conditions.push(Condition::matches("file_extension", extensions));
+    }
+
+    if filter.modified_after.is_some() || filter.modified_before.is_some() {
+        conditions.push(Condition::range(
+            "modified_time",
+            Range {
+                gte: filter.modified_after.map(|t| t as f64),
+                lt: filter.modified_before.map(|t| t as f64),
+                ..Default::default()
+            },
+        ));
+    }
+
+    if conditions.is_empty() {
+        None
+    } else {
+        Some(Filter::must(conditions))
+    }
+}
+
+impl From<FusionMethod> for Fusion {
+    fn from(method: FusionMethod) -> Self {
+        match method {
+            FusionMethod::Rrf => Fusion::Rrf,
+            FusionMethod::Dbsf => Fusion::Dbsf,
+        }
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ChunkMetadata {
-    pub file_id: String,
-    pub chunk_index: i32,
-    pub chunk_content: String,
+/// How dense vectors are compressed to fit more of them in memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Quantization {
+    /// Full 32-bit floats
+    None,
+    /// 8-bit integers: 4x smaller, with little loss in accuracy
+    Scalar,
+    /// One bit per dimension: 32x smaller, best for large embedding models
+    Binary,
 }
 
+/// Vector storage and index settings, fixed when the collections are created
 #[derive(Debug, Clone)]
-pub struct SearchResult {
-    pub file_path: String,
-    pub file_name: String,
-    pub chunk_content: String,
-    pub chunk_index: i32,
-    pub similarity_score: f64,
+pub struct CollectionSettings {
+    pub quantization: Quantization,
+    /// Keep vectors, the sparse index and payloads on disk instead of in memory
+    pub on_disk: bool,
+    /// Edges per node in the HNSW graph (Qdrant's default: 16)
+    pub hnsw_m: Option<u64>,
+    /// Neighbours considered while building the HNSW graph (Qdrant's default: 100)
+    pub hnsw_ef_construct: Option<u64>,
+    /// Weight sparse terms by inverse document frequency across the collection
+    pub sparse_idf: bool,
+}
+
+impl Default for CollectionSettings {
+    fn default() -> Self {
+        Self {
+            quantization: Quantization::None,
+            on_disk: false,
+            hnsw_m: None,
+            hnsw_ef_construct: None,
+            sparse_idf: false,
+        }
+    }
+}
+
+/// Connection settings for the Qdrant server, read from the environment
+pub struct QdrantSettings {
+    pub url: String,
+    api_key: Option<String>,
+    timeout: u64,
+    connect_timeout: u64,
+    keep_alive: bool,
+    compression: Option<CompressionEncoding>,
+}
+
+/// Version of the qdrant-client dependency in Cargo.toml; update both together. Servers more
+/// than one minor version away may reject requests.
+const CLIENT_VERSION: (u64, u64) = (1, 15);
+
+/// gRPC status codes that point at the API key
+const PERMISSION_DENIED: i32 = 7;
+const UNAUTHENTICATED: i32 = 16;
+
+impl QdrantSettings {
+    /// `QDRANT_URL`, `QDRANT_API_KEY`, `QDRANT_TIMEOUT`, `QDRANT_CONNECT_TIMEOUT`,
+    /// `QDRANT_KEEP_ALIVE` and `QDRANT_COMPRESSION`. The client only speaks gRPC, and TLS has
+    /// no settings of its own: it is on exactly when the URL is https://.
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let url = env::var("QDRANT_URL").map_err(|_| {
+            "Expected QDRANT_URL to be set in .env, or VECTOR_STORE=local to run without Qdrant"
+        })?;
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(format!(
+                "QDRANT_URL must be an http:// or https:// (TLS) URL of Qdrant's gRPC port, got '{}'",
+                url
+            )
+            .into());
+        }
+        let compression = match env::var("QDRANT_COMPRESSION")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "" | "none" => None,
+            "gzip" => Some(CompressionEncoding::Gzip),
+            other => {
+                return Err(format!("Unknown QDRANT_COMPRESSION '{}', expected gzip", other).into())
+            }
+        };
+
+        Ok(Self {
+            url,
+            api_key: env::var("QDRANT_API_KEY")
+                .ok()
+                .filter(|key| !key.is_empty()),
+            timeout: env_number("QDRANT_TIMEOUT", 5)?,
+            connect_timeout: env_number("QDRANT_CONNECT_TIMEOUT", 5)?,
+            keep_alive: !env::var("QDRANT_KEEP_ALIVE")
+                .is_ok_and(|value| matches!(value.to_lowercase().as_str(), "0" | "false" | "no")),
+            compression,
+        })
+    }
+
+    /// The error with a hint at the usual causes: a wrong key, or the REST port instead of gRPC
+    fn explain(&self, error: QdrantError) -> String {
+        let hint = match &error {
+            QdrantError::ResponseError { status }
+                if matches!(status.code() as i32, UNAUTHENTICATED | PERMISSION_DENIED) =>
+            {
+                if self.api_key.is_some() {
+                    "Check that QDRANT_API_KEY is valid for this server".to_string()
+                } else {
+                    "The server requires an API key; set QDRANT_API_KEY".to_string()
+                }
+            }
+            _ if self.url.trim_end_matches('/').ends_with(":6333") => {
+                "Port 6333 is Qdrant's REST API; this tool connects over gRPC, usually on port 6334"
+                    .to_string()
+            }
+            _ => format!("Make sure Qdrant is running on {}", self.url),
+        };
+
+        format!("{}. {}", error, hint)
+    }
 }
 
 pub struct QdrantVectorStore {
     client: Qdrant,
     collection_name: String,
     files_collection_name: String,
+    server_version: String,
 }
 
 const SPARSE_NAME: &str = "text-sparse";
 const DENSE_NAME: &str = "text-dense";
 
+/// Payload indexes backing the search filters and per-file chunk lookups
+const PAYLOAD_INDEXES: [(&str, FieldType); 4] = [
+    ("file_id", FieldType::Keyword),
+    ("path_prefixes", FieldType::Keyword),
+    ("file_extension", FieldType::Keyword),
+    ("modified_time", FieldType::Float),
+];
+
+/// Number of points fetched per scroll request when listing files
+const SCROLL_PAGE_SIZE: u32 = 256;
+
+fn payload_string(point: &RetrievedPoint, key: &str) -> String {
+    point.get(key).as_str().cloned().unwrap_or_default()
+}
+
+// Every ancestor of the path plus the path itself, so a prefix filter becomes a keyword match
+fn path_prefixes(file_path: &str) -> Vec<String> {
+    let mut prefixes = Path::new(file_path)
+        .ancestors()
+        .filter_map(|p| p.to_str())
+        .filter(|p| !p.is_empty())
+        .map(|p| p.to_string())
+        .collect::<Vec<_>>();
+    prefixes.reverse();
+    prefixes
+}
+
+/// Chunks are keyed by their document and position, so re-indexing a document overwrites
+/// its chunks in place
+fn chunk_point_id(file_id: &str, chunk_index: usize) -> String {
+    let hash = md5::compute(format!("{}:{}", file_id, chunk_index));
+    Uuid::from_bytes(hash.0).to_string()
+}
+
+/// The chunks of one document
+fn file_filter(file_id: &str) -> Filter {
+    Filter::must([Condition::matches("file_id", file_id.to_string())])
+}
+
+fn sparse_vector(embedding: &SparseEmbedding) -> (Vec<u32>, Vec<f32>) {
+    (
+        embedding.indices.iter().map(|&i| i as u32).collect(),
+        embedding.values.clone(),
+    )
+}
+
+fn search_result(point: &ScoredPoint) -> SearchResult {
+    SearchResult::new(
+        point.get("file_path").as_str().cloned().unwrap_or_default(),
+        point
+            .get("chunk_content")
+            .as_str()
+            .cloned()
+            .unwrap_or_default(),
+        payload_u64(point.get("chunk_index")) as i32,
+        point.score as f64,
+    )
+}
+
+// Numbers are written as f64 by `store_file_metadata`, but accept integers too
+fn payload_u64(value: &Value) -> u64 {
+    value
+        .as_double()
+        .map(|v| v as u64)
+        .or_else(|| value.as_integer().map(|v| v as u64))
+        .unwrap_or_default()
+}
+
+/// Warn when the server is too old or new for the client, as the client itself would
+fn check_server_version(version: &str) {
+    let mut parts = version.split('.').map(|part| part.parse::<u64>().ok());
+    let (Some(Some(major)), Some(Some(minor))) = (parts.next(), parts.next()) else {
+        debug!("Unrecognized Qdrant server version {}", version);
+        return;
+    };
+
+    if major != CLIENT_VERSION.0 || minor.abs_diff(CLIENT_VERSION.1) > 1 {
+        warn!(
+            "Qdrant server {} may not be compatible with this client ({}.{}); \
+             upgrade the server if requests fail",
+            version, CLIENT_VERSION.0, CLIENT_VERSION.1
+        );
+    }
+}
+
+/// Whether the URL's host is this machine, where plain http doesn't expose the API key
+fn is_loopback(url: &str) -> bool {
+    let Ok(url) = reqwest::Url::parse(url) else {
+        return false;
+    };
+    match url.host_str() {
+        Some("localhost") => true,
+        Some(host) => host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback()),
+        None => false,
+    }
+}
+
 impl QdrantVectorStore {
-    pub async fn new(url: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let client = Qdrant::from_url(url).build()?;
+    /// Connect and check the server is reachable, without touching the collections
+    pub async fn connect(settings: &QdrantSettings) -> Result<Self, Box<dyn std::error::Error>> {
+        if settings.api_key.is_some()
+            && settings.url.starts_with("http://")
+            && !is_loopback(&settings.url)
+        {
+            warn!("QDRANT_API_KEY is sent unencrypted; use an https:// QDRANT_URL");
+        }
 
-        let store = Self {
+        let mut config = Qdrant::from_url(&settings.url)
+            .api_key(settings.api_key.clone())
+            .timeout(Duration::from_secs(settings.timeout))
+            .connect_timeout(Duration::from_secs(settings.connect_timeout))
+            .compression(settings.compression)
+            // The client's own check prints to stdout, which would corrupt JSON output
+            .skip_compatibility_check();
+        config.set_keep_alive_while_idle(settings.keep_alive);
+        let client = config.build()?;
+
+        // Fail now with a clear message rather than on the first upsert
+        let health = client
+            .health_check()
+            .await
+            .map_err(|e| settings.explain(e))?;
+        client
+            .list_collections()
+            .await
+            .map_err(|e| settings.explain(e))?;
+        info!("Connected to Qdrant {} at {}", health.version, settings.url);
+        check_server_version(&health.version);
+
+        Ok(Self {
             client,
             collection_name: "file_embeddings".to_string(),
             files_collection_name: "files".to_string(),
-        };
+            server_version: health.version,
+        })
+    }
 
-        // Initialize collections
-        store.init_collections().await?;
+    pub fn server_version(&self) -> &str {
+        &self.server_version
+    }
 
-        Ok(store)
+    async fn missing_collections(&self) -> Result<Vec<&str>, Box<dyn std::error::Error>> {
+        let mut missing = Vec::new();
+        for name in [&self.files_collection_name, &self.collection_name] {
+            if !self.client.collection_exists(name).await? {
+                missing.push(name.as_str());
+            }
+        }
+        Ok(missing)
     }
 
-    async fn init_collections(&self) -> Result<(), Box<dyn std::error::Error>> {
-        // Create files collection (for metadata storage)
-        let files_response = self
-            .client
+    /// Create the collections with default settings if they don't exist yet, so a first run
+    /// works without `ssc index create`, and any payload index they lack
+    pub async fn ensure_collections(&self) -> Result<(), Box<dyn std::error::Error>> {
+        match self.missing_collections().await?.as_slice() {
+            [] => self.ensure_payload_indexes().await,
+            [missing] => Err(format!(
+                "Collection {} is missing while the other exists; delete both and run \
+                 `ssc index create` to start over",
+                missing
+            )
+            .into()),
+            _ => {
+                info!("Creating the Qdrant collections with default settings");
+                self.create_collections(&CollectionSettings::default())
+                    .await
+            }
+        }
+    }
+
+    /// Create both collections and the payload indexes behind the search filters. Fails if
+    /// either exists, since vector storage settings can't be changed without reindexing.
+    pub async fn create_collections(
+        &self,
+        settings: &CollectionSettings,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let missing = self.missing_collections().await?;
+        for name in [&self.files_collection_name, &self.collection_name] {
+            if !missing.contains(&name.as_str()) {
+                return Err(format!(
+                    "Collection {} already exists. Its settings are fixed at creation; delete it \
+                     (losing the indexed documents) to create it again",
+                    name
+                )
+                .into());
+            }
+        }
+
+        // Metadata only, so the single-dimension vector is a placeholder
+        self.client
             .create_collection(
                 CreateCollectionBuilder::new(&self.files_collection_name)
-                    .vectors_config(VectorParamsBuilder::new(1, Distance::Cosine)),
+                    .vectors_config(VectorParamsBuilder::new(1, Distance::Cosine))
+                    .on_disk_payload(settings.on_disk),
             )
-            .await;
+            .await?;
+        info!("Created collection {}", self.files_collection_name);
 
-        match files_response {
-            Ok(_) => println!("Files collection created successfully"),
-            Err(e) => println!("Files collection creation result: {:?}", e),
+        let mut hnsw = HnswConfigDiffBuilder::default();
+        if let Some(m) = settings.hnsw_m {
+            hnsw = hnsw.m(m);
+        }
+        if let Some(ef_construct) = settings.hnsw_ef_construct {
+            hnsw = hnsw.ef_construct(ef_construct);
         }
 
+        let mut dense = VectorParamsBuilder::new(DENSE_DIMENSIONS as u64, Distance::Cosine)
+            .on_disk(settings.on_disk)
+            .hnsw_config(hnsw);
+        // Quantized vectors stay in RAM for the search; the originals rescore the best matches
+        dense = match settings.quantization {
+            Quantization::None => dense,
+            Quantization::Scalar => {
+                dense.quantization_config(ScalarQuantizationBuilder::default().always_ram(true))
+            }
+            Quantization::Binary => dense.quantization_config(BinaryQuantizationBuilder::new(true)),
+        };
         let mut vector_config = VectorsConfigBuilder::default();
-        vector_config
-            .add_named_vector_params(DENSE_NAME, VectorParamsBuilder::new(384, Distance::Cosine));
+        vector_config.add_named_vector_params(DENSE_NAME, dense);
 
+        let mut sparse = SparseVectorParamsBuilder::default()
+            .index(SparseIndexConfigBuilder::default().on_disk(settings.on_disk));
+        if settings.sparse_idf {
+            sparse = sparse.modifier(Modifier::Idf);
+        }
         let mut sparse_vector_config = SparseVectorsConfigBuilder::default();
+        sparse_vector_config.add_named_vector_params(SPARSE_NAME, sparse);
 
-        sparse_vector_config
-            .add_named_vector_params(SPARSE_NAME, SparseVectorParamsBuilder::default());
-
-        // Create file embeddings collection
-        let dense_response = self
-            .client
+        self.client
             .create_collection(
                 CreateCollectionBuilder::new(&self.collection_name)
                     .vectors_config(vector_config)
-                    .sparse_vectors_config(sparse_vector_config),
+                    .sparse_vectors_config(sparse_vector_config)
+                    .on_disk_payload(settings.on_disk),
             )
-            .await;
+            .await?;
+        info!(
+            "Created collection {} with {:?}",
+            self.collection_name, settings
+        );
+
+        self.ensure_payload_indexes().await
+    }
 
-        match dense_response {
-            Ok(_) => println!("Dense collection created successfully"),
-            Err(e) => println!("Dense collection creation result: {:?}", e),
+    /// Create the payload indexes the chunk collection lacks, e.g. because an older version
+    /// created it. Without them filtered searches and per-file lookups scan every point.
+    async fn ensure_payload_indexes(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let existing = self
+            .client
+            .collection_info(&self.collection_name)
+            .await?
+            .result
+            .map(|info| info.payload_schema)
+            .unwrap_or_default();
+
+        for (field_name, field_type) in PAYLOAD_INDEXES {
+            if existing.contains_key(field_name) {
+                continue;
+            }
+            info!(
+                "Creating the {} payload index on {}",
+                field_name, self.collection_name
+            );
+            self.client
+                .create_field_index(CreateFieldIndexCollectionBuilder::new(
+                    &self.collection_name,
+                    field_name,
+                    field_type,
+                ))
+                .await?;
         }
 
         Ok(())
     }
 
-    pub async fn store_file_metadata(
+    async fn count_chunks(&self, file_id: &str) -> Result<u64, Box<dyn std::error::Error>> {
+        let response = self
+            .client
+            .count(
+                CountPointsBuilder::new(&self.collection_name)
+                    .filter(file_filter(file_id))
+                    .exact(true),
+            )
+            .await?;
+
+        Ok(response.result.map(|r| r.count).unwrap_or_default())
+    }
+}
+
+impl VectorStore for QdrantVectorStore {
+    async fn store_file_metadata(
         &self,
-        file_path: &str,
-        file_name: &str,
-        file_size: u64,
-        modified_time: u64,
-        content_hash: &str,
-        markdown_content: Option<&str>,
+        file: &FileMetadata,
+        chunk_count: usize,
     ) -> Result<String, Box<dyn std::error::Error>> {
-        let file_id = format!("{:x}", md5::compute(file_path));
-
-        let metadata = FileMetadata {
-            file_path: file_path.to_string(),
-            file_name: file_name.to_string(),
-            file_size,
-            modified_time,
-            content_hash: content_hash.to_string(),
-            markdown_content: markdown_content.map(|s| s.to_string()),
-        };
+        let file_id = file_id_for_path(&file.file_path);
 
         let point = PointStruct::new(
             file_id.clone(),
             vec![0.0], // Dummy vector for metadata collection
             Payload::try_from(json!({
-                "file_path": metadata.file_path,
-                "file_name": metadata.file_name,
-                "file_size": metadata.file_size as f64,
-                "modified_time": metadata.modified_time as f64,
-                "content_hash": metadata.content_hash,
-                "markdown_content": metadata.markdown_content.unwrap_or_default(),
+                "file_path": file.file_path,
+                "file_name": file.file_name,
+                "file_size": file.file_size as f64,
+                "modified_time": file.modified_time as f64,
+                "content_hash": file.content_hash,
+                "markdown_content": file.markdown_content.as_deref().unwrap_or_default(),
             }))
             .unwrap(),
         );
 
+        // The new chunks overwrote the old ones with the same index; delete the rest, including
+        // any with random IDs from older versions
+        let mut stale = file_filter(&file_id);
+        stale.must_not = vec![Condition::has_id(
+            (0..chunk_count).map(|chunk_index| chunk_point_id(&file_id, chunk_index)),
+        )];
+        self.client
+            .delete_points(
+                DeletePointsBuilder::new(&self.collection_name)
+                    .points(stale)
+                    .wait(true),
+            )
+            .await?;
         self.client
             .upsert_points(UpsertPointsBuilder::new(
                 &self.files_collection_name,
@@ -148,37 +538,29 @@ impl QdrantVectorStore {
         Ok(file_id)
     }
 
-    pub async fn store_embeddings(
+    async fn store_embeddings(
         &self,
-        file_id: &str,
-        chunk: &String,
-        chunk_index: i32,
-        dense_embedding: &Vec<f32>,
-        sparse_embedding: &fastembed::SparseEmbedding,
+        chunk: &ChunkMetadata,
+        dense_embedding: &[f32],
+        sparse_embedding: &SparseEmbedding,
     ) -> Result<(), Box<dyn std::error::Error>> {
+        let (indices, values) = sparse_vector(sparse_embedding);
         let embeddings_response = self.client.upsert_points(
             UpsertPointsBuilder::new(
                 &self.collection_name,
                 vec![PointStruct::new(
-                    Uuid::new_v4().to_string(),
+                    chunk_point_id(&chunk.file_id, chunk.chunk_index as usize),
                     NamedVectors::default()
-                        .add_vector(DENSE_NAME, Vector::new_dense(dense_embedding.clone()))
-                        .add_vector(
-                            SPARSE_NAME,
-                            Vector::new_sparse(
-                                sparse_embedding
-                                    .indices
-                                    .clone()
-                                    .into_iter()
-                                    .map(|i| i as u32)
-                                    .collect::<Vec<u32>>(),
-                                sparse_embedding.values.clone(),
-                            ),
-                        ),
+                        .add_vector(DENSE_NAME, Vector::new_dense(dense_embedding.to_vec()))
+                        .add_vector(SPARSE_NAME, Vector::new_sparse(indices, values)),
                     Payload::try_from(json!({
-                        "file_id": file_id.to_string(),
-                        "chunk_index": chunk_index as f64,
-                        "chunk_content": chunk,
+                        "file_id": chunk.file_id,
+                        "file_path": chunk.file_path,
+                        "path_prefixes": path_prefixes(&chunk.file_path),
+                        "file_extension": chunk.file_extension,
+                        "modified_time": chunk.modified_time as f64,
+                        "chunk_index": chunk.chunk_index as f64,
+                        "chunk_content": chunk.chunk_content,
                     }))
                     .unwrap(),
                 )],
@@ -186,111 +568,187 @@ impl QdrantVectorStore {
             .wait(true),
         );
 
-        match embeddings_response.await {
-            Ok(_) => {}
-            Err(e) => println!("Embeddings storage error result: {:?}", e),
-        };
+        embeddings_response
+            .await
+            .map_err(|e| format!("Failed to store embeddings for {}: {}", chunk.file_path, e))?;
 
         Ok(())
     }
 
-    pub async fn hybrid_search(
+    async fn list_files(&self) -> Result<Vec<IndexedFile>, Box<dyn std::error::Error>> {
+        let mut files = Vec::new();
+        let mut offset: Option<PointId> = None;
+
+        loop {
+            let mut request = ScrollPointsBuilder::new(&self.files_collection_name)
+                .limit(SCROLL_PAGE_SIZE)
+                // Skip markdown_content, it can be megabytes per document
+                .with_payload(PayloadIncludeSelector::new(vec![
+                    "file_path".to_string(),
+                    "file_size".to_string(),
+                    "modified_time".to_string(),
+                    "content_hash".to_string(),
+                ]));
+            if let Some(offset) = offset.take() {
+                request = request.offset(offset);
+            }
+
+            let response = self.client.scroll(request).await?;
+
+            for point in &response.result {
+                let file_path = payload_string(point, "file_path");
+                let chunk_count = self.count_chunks(&file_id_for_path(&file_path)).await?;
+
+                files.push(IndexedFile {
+                    file_size: payload_u64(point.get("file_size")),
+                    modified_time: payload_u64(point.get("modified_time")),
+                    content_hash: payload_string(point, "content_hash"),
+                    file_path,
+                    chunk_count,
+                });
+            }
+
+            match response.next_page_offset {
+                Some(next) => offset = Some(next),
+                None => break,
+            }
+        }
+
+        Ok(files)
+    }
+
+    async fn get_file(
+        &self,
+        file_path: &str,
+    ) -> Result<Option<FileMetadata>, Box<dyn std::error::Error>> {
+        let response = self
+            .client
+            .get_points(
+                GetPointsBuilder::new(
+                    &self.files_collection_name,
+                    vec![file_id_for_path(file_path).into()],
+                )
+                .with_payload(true),
+            )
+            .await?;
+
+        Ok(response.result.first().map(|point| FileMetadata {
+            file_path: payload_string(point, "file_path"),
+            file_name: payload_string(point, "file_name"),
+            file_size: payload_u64(point.get("file_size")),
+            modified_time: payload_u64(point.get("modified_time")),
+            content_hash: payload_string(point, "content_hash"),
+            markdown_content: point.get("markdown_content").as_str().cloned(),
+        }))
+    }
+
+    async fn retrieve(
         &self,
-        query: &str,
-        dense_model: &mut fastembed::TextEmbedding,
-        sparse_model: &mut fastembed::SparseTextEmbedding,
-        reranker: &mut fastembed::TextRerank,
+        queries: &[&str],
+        models: &mut SearchModels,
+        options: &SearchOptions,
     ) -> Result<Vec<SearchResult>, Box<dyn std::error::Error>> {
-        let overall_start = Instant::now();
-
-        // Generate sparse embeddings
-        let sparse_start = Instant::now();
-        let sparse_query_embeddings = sparse_model.embed(vec![query.to_string()], None)?;
-        let sparse_duration = sparse_start.elapsed();
-        println!("Sparse embedding generation: {:?}", sparse_duration);
-
-        // Generate dense embeddings
-        let dense_start = Instant::now();
-        let dense_query_embeddings = dense_model.embed(vec![query.to_string()], None)?;
-        let dense_duration = dense_start.elapsed();
-        println!("Dense embedding generation: {:?}", dense_duration);
-
-        let sparse_query_embedding = sparse_query_embeddings.first().unwrap();
-        let query_sparse = qdrant_client::qdrant::VectorInput::new_sparse(
-            sparse_query_embedding
-                .indices
-                .clone()
-                .into_iter()
-                .map(|i| i as u32)
-                .collect::<Vec<u32>>(),
-            sparse_query_embedding.values.clone(),
-        );
-        let dense_query_embedding = dense_query_embeddings.first().unwrap();
-        let query_dense =
-            qdrant_client::qdrant::VectorInput::new_dense(dense_query_embedding.clone());
+        let qdrant_filter = qdrant_filter(&options.filter);
+        let request = QueryPointsBuilder::new(&self.collection_name).with_payload(true);
+
+        let request = match (options.mode, queries) {
+            (SearchMode::Dense | SearchMode::Sparse, [query]) => {
+                let (query_vector, vector_name) = if options.mode == SearchMode::Dense {
+                    (
+                        VectorInput::new_dense(
+                            embed_dense_query(query, models.dense.get()?).await?,
+                        ),
+                        DENSE_NAME,
+                    )
+                } else {
+                    let (indices, values) =
+                        sparse_vector(&embed_sparse_query(query, models.sparse.get()?)?);
+                    (VectorInput::new_sparse(indices, values), SPARSE_NAME)
+                };
+
+                let mut request = request
+                    .query(Query::new_nearest(query_vector))
+                    .using(vector_name)
+                    .limit(options.top_k as u64);
+                if let Some(qdrant_filter) = qdrant_filter {
+                    request = request.filter(qdrant_filter);
+                }
+                request
+            }
+            _ => {
+                let mut request = request;
+
+                // One prefetch per query and branch, all fused into a single candidate list
+                for query in queries {
+                    if options.mode != SearchMode::Dense {
+                        let (indices, values) =
+                            sparse_vector(&embed_sparse_query(query, models.sparse.get()?)?);
+                        let mut sparse_prefetch = PrefetchQueryBuilder::default()
+                            .query(Query::new_nearest(VectorInput::new_sparse(indices, values)))
+                            .using(SPARSE_NAME) // sparse embedding
+                            .limit(options.sparse_limit);
+                        if let Some(qdrant_filter) = &qdrant_filter {
+                            sparse_prefetch = sparse_prefetch.filter(qdrant_filter.clone());
+                        }
+                        request = request.add_prefetch(sparse_prefetch);
+                    }
+                    if options.mode != SearchMode::Sparse {
+                        let mut dense_prefetch = PrefetchQueryBuilder::default()
+                            .query(Query::new_nearest(VectorInput::new_dense(
+                                embed_dense_query(query, models.dense.get()?).await?,
+                            )))
+                            .using(DENSE_NAME) // dense embedding
+                            .limit(options.dense_limit);
+                        if let Some(qdrant_filter) = &qdrant_filter {
+                            dense_prefetch = dense_prefetch.filter(qdrant_filter.clone());
+                        }
+                        request = request.add_prefetch(dense_prefetch);
+                    }
+                }
+
+                request
+                    .query(Query::new_fusion(Fusion::from(options.fusion)))
+                    .limit(options.retrieve_limit())
+            }
+        };
 
         // Vector search query execution
         let search_start = Instant::now();
-        // 50 total results => 25 results from each embedding type, sorted by score
-        let vector_results = self
-            .client
-            .query(
-                QueryPointsBuilder::new(&self.collection_name)
-                    .add_prefetch(
-                        PrefetchQueryBuilder::default()
-                            .query(Query::new_nearest(query_sparse))
-                            .using(SPARSE_NAME) // sparse embedding
-                            .limit(25 as u64),
-                    )
-                    .add_prefetch(
-                        PrefetchQueryBuilder::default()
-                            .query(Query::new_nearest(query_dense))
-                            .using(DENSE_NAME) // dense embedding
-                            .limit(25 as u64),
-                    )
-                    .query(Query::new_fusion(Fusion::Rrf))
-                    .limit(50 as u64)
-                    .with_payload(true),
-            )
-            .await?;
+        let vector_results = self.client.query(request).await?;
         let search_duration = search_start.elapsed();
-        println!("Vector search query execution: {:?}", search_duration);
+        debug!("Vector search query execution: {:?}", search_duration);
 
-        let documents = vector_results
+        Ok(vector_results
             .result
             .iter()
-            .filter_map(|result| {
-                result
-                    .payload
-                    .get("chunk_content")
-                    .and_then(|v| v.as_str().map(|s| s.as_str()))
-            })
-            .collect::<Vec<&str>>();
-
-        println!("Documents found: {}", documents.len());
-
-        // Reranking step
-        let rerank_start = Instant::now();
-        println!("Reranking documents...");
-        // Re-rank the results using the reranker and take the top 10
-        let reranked_results = reranker.rerank(query, documents, true, None)?;
-        let rerank_duration = rerank_start.elapsed();
-        println!("Reranking: {:?}", rerank_duration);
-
-        let final_results = reranked_results.iter().take(10).collect::<Vec<_>>();
-
-        let overall_duration = overall_start.elapsed();
-        println!("Total hybrid search time: {:?}", overall_duration);
-
-        Ok(final_results
-            .into_iter()
-            .map(|result| SearchResult {
-                file_path: "".to_string(),
-                file_name: "".to_string(),
-                chunk_content: result.document.clone().unwrap_or_default(),
-                chunk_index: 0,
-                similarity_score: result.score as f64,
-            })
-            .collect::<Vec<_>>())
+            .filter(|result| result.get("chunk_content").is_str())
+            .map(search_result)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loopback_hosts() {
+        for url in [
+            "http://localhost:6334",
+            "http://127.0.0.1:6334",
+            "http://127.0.1.1",
+            "http://[::1]:6334",
+        ] {
+            assert!(is_loopback(url), "{}", url);
+        }
+        for url in [
+            "http://localhost.example.com:6334",
+            "http://qdrant.example.com/localhost",
+            "http://10.0.0.5:6334",
+            "http://[::2]:6334",
+            "not a url",
+        ] {
+            assert!(!is_loopback(url), "{}", url);
+        }
     }
 }
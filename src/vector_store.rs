@@ -0,0 +1,288 @@
+use crate::embeddings::Embedder;
+use crate::models::{self, SearchModels};
+use fastembed::{SparseEmbedding, SparseTextEmbedding};
+use log::{debug, trace};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Instant;
+
+/// Size of the dense vectors, fixed when the index is created
+pub const DENSE_DIMENSIONS: usize = 384;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileMetadata {
+    pub file_path: String,
+    pub file_name: String,
+    pub file_size: u64,
+    pub modified_time: u64,
+    pub content_hash: String,
+    pub markdown_content: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkMetadata {
+    pub file_id: String,
+    pub file_path: String,
+    pub file_extension: String,
+    pub modified_time: u64,
+    pub chunk_index: i32,
+    pub chunk_content: String,
+}
+
+/// Filters on chunk metadata, applied to every candidate list of a search
+#[derive(Debug, Clone, Default)]
+pub struct SearchFilter {
+    pub path_prefix: Option<String>,
+    pub extensions: Vec<String>,
+    pub modified_after: Option<u64>,
+    pub modified_before: Option<u64>,
+}
+
+/// How the sparse and dense candidate lists are combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FusionMethod {
+    /// Reciprocal Rank Fusion
+    Rrf,
+    /// Distribution-Based Score Fusion
+    Dbsf,
+}
+
+/// Which retrieval stages a search runs, so each stage's contribution can be compared
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SearchMode {
+    /// Dense vectors only
+    Dense,
+    /// Sparse (SPLADE) vectors only
+    Sparse,
+    /// Fused dense and sparse results, without reranking
+    Hybrid,
+    /// Fused dense and sparse results, reranked
+    Full,
+}
+
+/// Retrieval depth and cutoffs for a hybrid search
+#[derive(Debug, Clone)]
+pub struct SearchOptions {
+    pub filter: SearchFilter,
+    pub mode: SearchMode,
+    /// Candidates fetched from the sparse index
+    pub sparse_limit: u64,
+    /// Candidates fetched from the dense index
+    pub dense_limit: u64,
+    pub fusion: FusionMethod,
+    /// Fused candidates passed on to the reranker
+    pub rerank_limit: u64,
+    /// Results returned after reranking
+    pub top_k: usize,
+    /// Drop reranked results scoring below this (full mode only)
+    pub min_score: Option<f32>,
+}
+
+impl SearchOptions {
+    /// Candidates to retrieve: enough to rerank in full mode, otherwise the final results
+    pub fn retrieve_limit(&self) -> u64 {
+        if self.mode == SearchMode::Full {
+            self.rerank_limit
+        } else {
+            self.top_k as u64
+        }
+    }
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        Self {
+            filter: SearchFilter::default(),
+            mode: SearchMode::Full,
+            sparse_limit: 25,
+            dense_limit: 25,
+            fusion: FusionMethod::Rrf,
+            rerank_limit: 50,
+            top_k: 10,
+            min_score: None,
+        }
+    }
+}
+
+/// Summary of an indexed document, as shown by `ssc ls`
+#[derive(Debug, Clone, Serialize)]
+pub struct IndexedFile {
+    pub file_path: String,
+    pub file_size: u64,
+    pub modified_time: u64,
+    pub content_hash: String,
+    pub chunk_count: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchResult {
+    pub file_path: String,
+    pub file_name: String,
+    pub chunk_content: String,
+    pub chunk_index: i32,
+    pub similarity_score: f64,
+}
+
+impl SearchResult {
+    pub fn new(
+        file_path: String,
+        chunk_content: String,
+        chunk_index: i32,
+        similarity_score: f64,
+    ) -> Self {
+        Self {
+            file_name: Path::new(&file_path)
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or_default()
+                .to_string(),
+            file_path,
+            chunk_content,
+            chunk_index,
+            similarity_score,
+        }
+    }
+}
+
+/// A path filter without trailing slashes, or `None` for an empty one or `/`, which every
+/// path is under
+pub fn path_filter_prefix(prefix: &str) -> Option<&str> {
+    Some(prefix.trim_end_matches('/')).filter(|prefix| !prefix.is_empty())
+}
+
+/// Whether `file_path` is `prefix` or inside it. Whole path components are compared, so
+/// `docs` matches `docs/a.md` but not `docs-old/a.md`.
+pub fn in_path(file_path: &str, prefix: &str) -> bool {
+    path_filter_prefix(prefix).is_none_or(|prefix| Path::new(file_path).starts_with(prefix))
+}
+
+/// Files are keyed by the md5 of their path, so a re-indexed file keeps its ID
+pub fn file_id_for_path(file_path: &str) -> String {
+    format!("{:x}", md5::compute(file_path))
+}
+
+pub fn embed_sparse_query(
+    query: &str,
+    sparse_model: &mut SparseTextEmbedding,
+) -> Result<SparseEmbedding, Box<dyn std::error::Error>> {
+    let sparse_start = Instant::now();
+    let sparse_query_embeddings =
+        sparse_model.embed(vec![models::SPARSE_PREFIXES.query(query)], None)?;
+    let sparse_duration = sparse_start.elapsed();
+    debug!("Sparse embedding generation: {:?}", sparse_duration);
+
+    sparse_query_embeddings
+        .into_iter()
+        .next()
+        .ok_or_else(|| "The sparse model returned no embedding for the query".into())
+}
+
+pub async fn embed_dense_query(
+    query: &str,
+    embedder: &mut Embedder,
+) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+    let dense_start = Instant::now();
+    let dense_query_embedding = embedder.embed_query(query).await?;
+    let dense_duration = dense_start.elapsed();
+    debug!("Dense embedding generation: {:?}", dense_duration);
+
+    Ok(dense_query_embedding)
+}
+
+/// Where documents and chunk embeddings are kept and searched: a Qdrant server, or a
+/// directory on disk when no server is available
+pub trait VectorStore {
+    /// Store a document's metadata and markdown once all `chunk_count` of its chunks are
+    /// stored, returning its file ID. This completes re-indexing a document: chunks left over
+    /// from its previous version are deleted only now, so if storing a chunk fails the previous
+    /// version stays searchable and keeps its old hash.
+    async fn store_file_metadata(
+        &self,
+        file: &FileMetadata,
+        chunk_count: usize,
+    ) -> Result<String, Box<dyn std::error::Error>>;
+
+    /// Store one chunk of a document, before its metadata. Chunks are stored in order of
+    /// `chunk_index`, starting at 0.
+    async fn store_embeddings(
+        &self,
+        chunk: &ChunkMetadata,
+        dense_embedding: &[f32],
+        sparse_embedding: &SparseEmbedding,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// List every indexed document along with its chunk count
+    async fn list_files(&self) -> Result<Vec<IndexedFile>, Box<dyn std::error::Error>>;
+
+    /// Fetch the stored metadata (including markdown) for a single document path
+    async fn get_file(
+        &self,
+        file_path: &str,
+    ) -> Result<Option<FileMetadata>, Box<dyn std::error::Error>>;
+
+    /// Up to `options.retrieve_limit()` candidates for `queries`, the user's query first, with
+    /// the branches of `options.mode` fused but nothing reranked
+    async fn retrieve(
+        &self,
+        queries: &[&str],
+        models: &mut SearchModels,
+        options: &SearchOptions,
+    ) -> Result<Vec<SearchResult>, Box<dyn std::error::Error>>;
+
+    /// Search for `query`. Any `extra_queries` (e.g. LLM reformulations) are searched too and
+    /// their candidates fused with the query's own; reranking always scores against `query`.
+    async fn hybrid_search(
+        &self,
+        query: &str,
+        extra_queries: &[String],
+        models: &mut SearchModels,
+        options: &SearchOptions,
+    ) -> Result<Vec<SearchResult>, Box<dyn std::error::Error>> {
+        let overall_start = Instant::now();
+        let queries = std::iter::once(query)
+            .chain(extra_queries.iter().map(String::as_str))
+            .collect::<Vec<_>>();
+
+        let candidates = self.retrieve(&queries, models, options).await?;
+        debug!("Documents found: {}", candidates.len());
+
+        if options.mode != SearchMode::Full {
+            let overall_duration = overall_start.elapsed();
+            debug!(
+                "Total {:?} search time: {:?}",
+                options.mode, overall_duration
+            );
+            return Ok(candidates);
+        }
+
+        let documents = candidates
+            .iter()
+            .map(|result| result.chunk_content.as_str())
+            .collect::<Vec<&str>>();
+
+        // Reranking step
+        let rerank_start = Instant::now();
+        trace!("Reranking documents...");
+        // Re-rank the results using the reranker and keep the best top_k above the cutoff
+        let reranked_results = models
+            .reranker
+            .get()?
+            .rerank(query, documents, false, None)?;
+        let rerank_duration = rerank_start.elapsed();
+        debug!("Reranking: {:?}", rerank_duration);
+
+        let overall_duration = overall_start.elapsed();
+        debug!("Total hybrid search time: {:?}", overall_duration);
+
+        Ok(reranked_results
+            .iter()
+            .filter(|result| options.min_score.is_none_or(|min| result.score >= min))
+            .take(options.top_k)
+            // The reranker index points back into the candidates passed to it
+            .map(|result| SearchResult {
+                similarity_score: result.score as f64,
+                ..candidates[result.index].clone()
+            })
+            .collect())
+    }
+}